@@ -0,0 +1,59 @@
+//! Measures instruction dispatch speed with and without predecoding
+//!
+//! Run with `cargo bench`.
+
+use iridium::config::VmConfig;
+use iridium::opcode::Opcode;
+use iridium::vm::RunResult;
+use std::time::{Duration, Instant};
+
+/// The number of instructions executed per run
+const INSTRUCTIONS: u64 = 5_000_000;
+/// The number of runs whose times are averaged
+const RUNS: u32 = 5;
+
+/// Builds a loop of register moves and loads, including extended instructions
+fn program() -> Vec<u8> {
+    let mut program = vec![];
+    for register in 0..8 {
+        program.extend(&[Opcode::EXT2 as u8, Opcode::LOADI as u8, register, 1, 244]);
+        program.extend(&[Opcode::MOVE as u8, register + 8, register]);
+        program.push(Opcode::NOP as u8);
+    }
+    program.extend(&[Opcode::JMP as u8, 0, 0]);
+    program
+}
+
+/// Runs the program until its fuel is exhausted, returning the average time taken
+fn measure(predecode: bool) -> Duration {
+    let mut total = Duration::default();
+    for _ in 0..RUNS {
+        let mut vm = VmConfig::new()
+            .fuel(INSTRUCTIONS)
+            .predecode(predecode)
+            .build();
+        // Only execution is timed, so that predecoding the program is not counted
+        vm.load_program(program());
+        let start = Instant::now();
+        assert_eq!(vm.run(), RunResult::FuelExhausted);
+        total += start.elapsed();
+    }
+    total / RUNS
+}
+
+fn main() {
+    let decoded = measure(false);
+    let predecoded = measure(true);
+    for (name, time) in [("decode per step", decoded), ("predecoded", predecoded)] {
+        println!(
+            "{:<16} {:>10.2?} ({:.1} ns/instruction)",
+            name,
+            time,
+            time.as_nanos() as f64 / INSTRUCTIONS as f64
+        );
+    }
+    println!(
+        "speedup          {:>10.2}x",
+        decoded.as_secs_f64() / predecoded.as_secs_f64()
+    );
+}
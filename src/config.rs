@@ -0,0 +1,80 @@
+use crate::vm::VM;
+
+#[derive(Clone, Debug, PartialEq)]
+/// Configures the options used to create a Virtual Machine
+///
+/// A configuration is built by chaining option methods, starting from the defaults:
+///
+/// ```
+/// use iridium::config::VmConfig;
+///
+/// let vm = VmConfig::new().registers(64).fuel(10_000).build();
+/// assert_eq!(vm.registers().len(), 64);
+/// ```
+pub struct VmConfig {
+    pub(crate) register_count: usize,
+    pub(crate) fuel: Option<u64>,
+    pub(crate) predecode: bool,
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        VmConfig {
+            register_count: 32,
+            fuel: None,
+            predecode: true,
+        }
+    }
+}
+
+impl VmConfig {
+    /// Creates a configuration with the default options
+    pub fn new() -> VmConfig {
+        VmConfig::default()
+    }
+    /// Sets the number of registers available to the VM (32 by default)
+    pub fn registers(mut self, count: usize) -> VmConfig {
+        self.register_count = count;
+        self
+    }
+    /// Limits the amount of fuel available for executing instructions (unlimited by default)
+    ///
+    /// See [`VM::set_fuel`](../vm/struct.VM.html#method.set_fuel) for details.
+    pub fn fuel(mut self, fuel: u64) -> VmConfig {
+        self.fuel = Some(fuel);
+        self
+    }
+    /// Sets whether programs are decoded ahead of time when they are loaded (enabled by default)
+    ///
+    /// Decoding ahead of time allows instructions to be executed without re-reading their bytecode.
+    /// When disabled, each instruction is decoded from the bytecode every time it is executed.
+    pub fn predecode(mut self, predecode: bool) -> VmConfig {
+        self.predecode = predecode;
+        self
+    }
+    /// Creates a Virtual Machine using this configuration
+    pub fn build(self) -> VM {
+        VM::with_config(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_default_config() {
+        let config = VmConfig::new();
+        assert_eq!(config.register_count, 32);
+        assert_eq!(config.fuel, None);
+        assert!(config.predecode);
+        assert_eq!(config.build(), VM::new());
+    }
+    #[test]
+    fn test_builder() {
+        let config = VmConfig::new().registers(8).fuel(100).predecode(false);
+        assert_eq!(config.register_count, 8);
+        assert_eq!(config.fuel, Some(100));
+        assert!(!config.predecode);
+        assert_eq!(config.build().fuel(), Some(100));
+    }
+}
@@ -0,0 +1,227 @@
+use crate::opcode::{Opcode, OpcodeArgs};
+use crate::trace::Hook;
+use crate::vm::{Instruction, RunResult};
+use std::collections::BTreeMap;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+/// Counts how often a conditional jump was taken and how often it fell through
+pub struct BranchCounts {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+#[derive(Debug, Default)]
+/// A hook that records which instructions were executed
+///
+/// Instructions are identified by the program counter they were decoded from. Conditional jumps
+/// ([`JMPIF`](../opcode/enum.Opcode.html#variant.JMPIF) and
+/// [`JMPNOT`](../opcode/enum.Opcode.html#variant.JMPNOT)) also record whether they jumped, which
+/// is determined from the program counter the VM reaches after them. A conditional jump whose
+/// target is the instruction that follows it reaches the same place either way, so it counts as
+/// both taken and not taken.
+pub struct Coverage {
+    instructions: BTreeMap<usize, u64>,
+    branches: BTreeMap<usize, BranchCounts>,
+    pending_branch: Option<PendingBranch>,
+}
+
+#[derive(Debug)]
+/// A conditional jump whose outcome is not yet known
+struct PendingBranch {
+    pc: usize,
+    target: usize,
+    next_pc: usize,
+}
+
+impl Coverage {
+    /// Creates an empty coverage record
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+    /// Returns the number of times the instruction at a program counter was executed
+    pub fn hits(&self, pc: usize) -> u64 {
+        self.instructions.get(&pc).copied().unwrap_or(0)
+    }
+    /// Returns the program counters of all executed instructions with their execution counts,
+    /// in program order
+    pub fn instructions(&self) -> impl Iterator<Item = (usize, u64)> + '_ {
+        self.instructions.iter().map(|(pc, hits)| (*pc, *hits))
+    }
+    /// Returns the program counters of all executed conditional jumps with their branch counts,
+    /// in program order
+    pub fn branches(&self) -> impl Iterator<Item = (usize, BranchCounts)> + '_ {
+        self.branches.iter().map(|(pc, counts)| (*pc, *counts))
+    }
+    /// Discards all recorded coverage
+    pub fn reset(&mut self) {
+        *self = Coverage::default();
+    }
+    /// Records the outcome of the pending conditional jump, given the program counter the VM
+    /// reached after it
+    ///
+    /// A program counter that is neither the target nor the following instruction (such as when
+    /// the jump faulted) records no outcome.
+    fn resolve_branch(&mut self, pc: usize) {
+        if let Some(branch) = self.pending_branch.take() {
+            let counts = self.branches.entry(branch.pc).or_default();
+            if pc == branch.target {
+                counts.taken += 1;
+            }
+            if pc == branch.next_pc {
+                counts.not_taken += 1;
+            }
+        }
+    }
+}
+
+impl Hook for Coverage {
+    fn before_instruction(&mut self, pc: usize, instruction: &Instruction, _registers: &[i32]) {
+        self.resolve_branch(pc);
+        *self.instructions.entry(pc).or_insert(0) += 1;
+        if let Opcode::JMPIF | Opcode::JMPNOT = instruction.opcode() {
+            // The length of the instruction depends on whether its register was extended
+            let (target, length) = match instruction.args() {
+                OpcodeArgs::U8U16(_, target) => (target, 4),
+                OpcodeArgs::U16U16(_, target) => (target, 6),
+                _ => return,
+            };
+            self.pending_branch = Some(PendingBranch {
+                pc,
+                target: target as usize,
+                next_pc: pc + length,
+            });
+        }
+    }
+    fn after_run(&mut self, pc: usize, _result: &RunResult) {
+        self.resolve_branch(pc);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{Decoder, VmError, VM};
+    use std::sync::{Arc, Mutex};
+    #[test]
+    fn test_instruction_coverage() {
+        let coverage = Arc::new(Mutex::new(Coverage::new()));
+        let mut vm = VM::new();
+        vm.add_hook(Box::new(coverage.clone()));
+        vm.load_program(vec![
+            Opcode::JMP as u8,
+            0,
+            4,
+            Opcode::NOP as u8,
+            Opcode::NOP as u8,
+            Opcode::STOP as u8,
+        ]);
+        assert_eq!(vm.run(), RunResult::Halted);
+        let coverage = coverage.lock().unwrap();
+        assert_eq!(coverage.hits(0), 1);
+        assert_eq!(coverage.hits(3), 0);
+        assert_eq!(
+            coverage.instructions().collect::<Vec<_>>(),
+            vec![(0, 1), (4, 1), (5, 1)]
+        );
+        assert_eq!(coverage.branches().count(), 0);
+    }
+    #[test]
+    fn test_branch_coverage() {
+        let mut coverage = Coverage::new();
+        let branch = Instruction::new(Opcode::JMPIF, OpcodeArgs::U8U16(1, 10));
+        let nop = Instruction::new(Opcode::NOP, OpcodeArgs::None);
+        coverage.before_instruction(0, &branch, &[]);
+        coverage.before_instruction(10, &nop, &[]);
+        coverage.before_instruction(0, &branch, &[]);
+        coverage.before_instruction(4, &nop, &[]);
+        coverage.before_instruction(0, &branch, &[]);
+        coverage.before_instruction(10, &nop, &[]);
+        assert_eq!(coverage.hits(0), 3);
+        assert_eq!(
+            coverage.branches().collect::<Vec<_>>(),
+            vec![(
+                0,
+                BranchCounts {
+                    taken: 2,
+                    not_taken: 1
+                }
+            )]
+        );
+    }
+    #[test]
+    fn test_extended_branch_coverage() {
+        let program = [Opcode::EXT1 as u8, Opcode::JMPIF as u8, 1, 0, 0, 10];
+        let branch = Decoder::new(&program, 0)
+            .decode_instruction()
+            .unwrap()
+            .unwrap();
+        assert_eq!(branch.args(), OpcodeArgs::U16U16(256, 10));
+        let nop = Instruction::new(Opcode::NOP, OpcodeArgs::None);
+        let mut coverage = Coverage::new();
+        coverage.before_instruction(0, &branch, &[]);
+        coverage.before_instruction(10, &nop, &[]);
+        coverage.before_instruction(0, &branch, &[]);
+        coverage.before_instruction(6, &nop, &[]);
+        assert_eq!(
+            coverage.branches().collect::<Vec<_>>(),
+            vec![(
+                0,
+                BranchCounts {
+                    taken: 1,
+                    not_taken: 1
+                }
+            )]
+        );
+    }
+    #[test]
+    fn test_jump_to_next_instruction() {
+        let mut coverage = Coverage::new();
+        let branch = Instruction::new(Opcode::JMPNOT, OpcodeArgs::U8U16(1, 4));
+        let nop = Instruction::new(Opcode::NOP, OpcodeArgs::None);
+        coverage.before_instruction(0, &branch, &[]);
+        coverage.before_instruction(4, &nop, &[]);
+        assert_eq!(
+            coverage.branches().collect::<Vec<_>>(),
+            vec![(
+                0,
+                BranchCounts {
+                    taken: 1,
+                    not_taken: 1
+                }
+            )]
+        );
+    }
+    #[test]
+    fn test_after_run() {
+        let mut coverage = Coverage::new();
+        let branch = Instruction::new(Opcode::JMPNOT, OpcodeArgs::U8U16(1, 10));
+        let nop = Instruction::new(Opcode::NOP, OpcodeArgs::None);
+        coverage.before_instruction(0, &branch, &[]);
+        coverage.after_run(10, &RunResult::Halted);
+        // The next run must not resolve the branch again
+        coverage.before_instruction(0, &nop, &[]);
+        coverage.after_run(1, &RunResult::Halted);
+        coverage.before_instruction(0, &branch, &[]);
+        coverage.after_run(4, &RunResult::FuelExhausted);
+        // A branch that faulted has no outcome
+        coverage.before_instruction(0, &branch, &[]);
+        coverage.after_run(
+            0,
+            &RunResult::Fault(VmError::UnrecognizedOpcode(Opcode::JMPNOT)),
+        );
+        assert_eq!(coverage.hits(0), 4);
+        assert_eq!(
+            coverage.branches().collect::<Vec<_>>(),
+            vec![(
+                0,
+                BranchCounts {
+                    taken: 1,
+                    not_taken: 1
+                }
+            )]
+        );
+        coverage.reset();
+        assert_eq!(coverage.hits(0), 0);
+        assert_eq!(coverage.branches().count(), 0);
+    }
+}
@@ -0,0 +1,564 @@
+use crate::debugger::{DebugEvent, Debugger};
+use crate::vm::{RunResult, VM};
+use serde_json::{json, Value};
+use std::io::{self, BufRead, Write};
+
+/// The identifier of the only thread a Virtual Machine runs
+const THREAD_ID: i64 = 1;
+/// The variables reference used for the register scope
+const REGISTERS_REFERENCE: i64 = 1;
+
+/// Serves the Debug Adapter Protocol, driving a [`Debugger`](../debugger/struct.Debugger.html)
+///
+/// Messages are read from `input` and written to `output` using the protocol's
+/// `Content-Length` framing, which allows the server to communicate with an IDE over stdio.
+///
+/// Programs are raw bytecode files given by the `program` argument of the `launch` request.
+/// Since bytecode does not yet carry line information, breakpoints set through `setBreakpoints`
+/// are reported as unverified; breakpoints should instead be set by program counter through
+/// `setInstructionBreakpoints`, using the program counter as the instruction reference.
+pub struct DapServer<R, W> {
+    input: R,
+    output: W,
+    seq: i64,
+    debugger: Option<Debugger>,
+    stop_on_entry: bool,
+    terminated: bool,
+}
+
+impl<R: BufRead, W: Write> DapServer<R, W> {
+    /// Creates a server that reads requests from `input` and writes responses to `output`
+    pub fn new(input: R, output: W) -> DapServer<R, W> {
+        DapServer {
+            input,
+            output,
+            seq: 0,
+            debugger: None,
+            stop_on_entry: false,
+            terminated: false,
+        }
+    }
+    /// Handles requests until the client disconnects or the input is exhausted
+    pub fn run(&mut self) -> io::Result<()> {
+        while let Some(request) = self.read_message()? {
+            if !self.handle_request(&request)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+    /// Reads a single message, returning `None` at the end of the input
+    fn read_message(&mut self) -> io::Result<Option<Value>> {
+        let mut headers = 0;
+        let mut content_length = None;
+        let content_length = loop {
+            let mut header = String::new();
+            if self.input.read_line(&mut header)? == 0 {
+                return Ok(None);
+            }
+            let header = header.trim();
+            if header.is_empty() {
+                // Skip blank lines between messages, but not a header block without a length
+                match content_length {
+                    Some(length) => break length,
+                    None if headers == 0 => continue,
+                    None => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "Message header has no valid Content-Length",
+                        ))
+                    }
+                }
+            }
+            headers += 1;
+            if let Some(length) = header.strip_prefix("Content-Length:") {
+                content_length = length.trim().parse::<usize>().ok();
+            }
+        };
+        let mut content = vec![0; content_length];
+        self.input.read_exact(&mut content)?;
+        serde_json::from_slice(&content)
+            .map(Some)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+    /// Writes a single message, assigning it the next sequence number
+    fn write_message(&mut self, mut message: Value) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let content = message.to_string();
+        write!(
+            self.output,
+            "Content-Length: {}\r\n\r\n{}",
+            content.len(),
+            content
+        )?;
+        self.output.flush()
+    }
+    /// Writes a successful response to a request
+    fn respond(&mut self, request: &Value, body: Value) -> io::Result<()> {
+        self.write_message(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": body,
+        }))
+    }
+    /// Writes a failed response to a request
+    fn respond_error(&mut self, request: &Value, message: &str) -> io::Result<()> {
+        self.write_message(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": message,
+        }))
+    }
+    /// Writes an event
+    fn send_event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.write_message(json!({
+            "type": "event",
+            "event": event,
+            "body": body,
+        }))
+    }
+    /// Handles a single request, returning false once the client has disconnected
+    fn handle_request(&mut self, request: &Value) -> io::Result<bool> {
+        let command = request["command"].as_str().unwrap_or_default();
+        let arguments = &request["arguments"];
+        if self.debugger.is_none() && !matches!(command, "initialize" | "launch" | "disconnect") {
+            self.respond_error(request, "No program has been launched")?;
+            return Ok(true);
+        }
+        if self.terminated && matches!(command, "continue" | "next" | "stepIn" | "stepOut") {
+            self.respond_error(request, "The program has terminated")?;
+            return Ok(true);
+        }
+        match command {
+            "initialize" => {
+                self.respond(
+                    request,
+                    json!({
+                        "supportsConfigurationDoneRequest": true,
+                        "supportsInstructionBreakpoints": true,
+                    }),
+                )?;
+                self.send_event("initialized", json!({}))?;
+            }
+            "launch" => {
+                let path = arguments["program"].as_str().unwrap_or_default();
+                match std::fs::read(path) {
+                    Ok(program) => {
+                        let mut vm = VM::new();
+                        vm.load_program(program);
+                        if let Err(error) = vm.verify() {
+                            self.respond_error(request, &error.to_string())?;
+                            return Ok(true);
+                        }
+                        self.debugger = Some(Debugger::new(vm));
+                        self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+                        self.terminated = false;
+                        self.respond(request, json!({}))?;
+                    }
+                    Err(error) => {
+                        self.respond_error(request, &format!("Could not read {}: {}", path, error))?
+                    }
+                }
+            }
+            "setBreakpoints" => {
+                let breakpoints = arguments["breakpoints"]
+                    .as_array()
+                    .map(|breakpoints| breakpoints.len())
+                    .unwrap_or(0);
+                let breakpoints = (0..breakpoints)
+                    .map(|_| {
+                        json!({
+                            "verified": false,
+                            "message": "Line information is not available",
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                self.respond(request, json!({ "breakpoints": breakpoints }))?;
+            }
+            "setInstructionBreakpoints" => {
+                let debugger = self.debugger.as_mut().unwrap();
+                for pc in debugger.breakpoints().collect::<Vec<_>>() {
+                    debugger.clear_breakpoint(pc);
+                }
+                let mut breakpoints = vec![];
+                for breakpoint in arguments["breakpoints"].as_array().into_iter().flatten() {
+                    let pc = breakpoint["instructionReference"]
+                        .as_str()
+                        .and_then(|reference| reference.parse::<i64>().ok())
+                        .map(|pc| pc + breakpoint["offset"].as_i64().unwrap_or(0));
+                    match pc {
+                        Some(pc) if pc >= 0 && debugger.vm().is_instruction_start(pc as usize) => {
+                            debugger.set_breakpoint(pc as usize);
+                            breakpoints.push(json!({
+                                "verified": true,
+                                "instructionReference": pc.to_string(),
+                            }));
+                        }
+                        _ => breakpoints.push(json!({
+                            "verified": false,
+                            "message": "Not the start of an instruction",
+                        })),
+                    }
+                }
+                self.respond(request, json!({ "breakpoints": breakpoints }))?;
+            }
+            "configurationDone" => {
+                self.respond(request, json!({}))?;
+                if self.stop_on_entry {
+                    self.send_stopped("entry", None)?;
+                } else {
+                    let event = self.debugger.as_mut().unwrap().resume();
+                    self.report(event)?;
+                }
+            }
+            "threads" => {
+                self.respond(
+                    request,
+                    json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] }),
+                )?;
+            }
+            "stackTrace" => {
+                let pc = self.debugger.as_ref().unwrap().vm().pc();
+                self.respond(
+                    request,
+                    json!({
+                        "stackFrames": [{
+                            "id": 0,
+                            "name": "<main>",
+                            "line": 0,
+                            "column": 0,
+                            "instructionPointerReference": pc.to_string(),
+                        }],
+                        "totalFrames": 1,
+                    }),
+                )?;
+            }
+            "scopes" => {
+                self.respond(
+                    request,
+                    json!({
+                        "scopes": [{
+                            "name": "Registers",
+                            "variablesReference": REGISTERS_REFERENCE,
+                            "expensive": false,
+                        }],
+                    }),
+                )?;
+            }
+            "variables" => {
+                let variables = if arguments["variablesReference"] == json!(REGISTERS_REFERENCE) {
+                    self.debugger
+                        .as_ref()
+                        .unwrap()
+                        .vm()
+                        .registers()
+                        .iter()
+                        .enumerate()
+                        .map(|(index, value)| {
+                            json!({
+                                "name": format!("r{}", index),
+                                "value": value.to_string(),
+                                "variablesReference": 0,
+                            })
+                        })
+                        .collect()
+                } else {
+                    vec![]
+                };
+                self.respond(request, json!({ "variables": variables }))?;
+            }
+            "continue" => {
+                self.respond(request, json!({ "allThreadsContinued": true }))?;
+                let event = self.debugger.as_mut().unwrap().resume();
+                self.report(event)?;
+            }
+            // There are no call frames to step into, over, or out of, so each is a single step
+            "next" | "stepIn" | "stepOut" => {
+                self.respond(request, json!({}))?;
+                let event = self.debugger.as_mut().unwrap().step();
+                self.report(event)?;
+            }
+            "disconnect" => {
+                self.respond(request, json!({}))?;
+                return Ok(false);
+            }
+            _ => {
+                let message = format!("Unrecognized command: {}", command);
+                self.respond_error(request, &message)?;
+            }
+        }
+        Ok(true)
+    }
+    /// Sends the events describing why execution paused
+    fn report(&mut self, event: DebugEvent) -> io::Result<()> {
+        match event {
+            DebugEvent::Breakpoint(_) => self.send_stopped("instruction breakpoint", None),
+            DebugEvent::Stepped => self.send_stopped("step", None),
+            DebugEvent::Stopped(RunResult::Halted) => {
+                self.terminated = true;
+                self.send_event("exited", json!({ "exitCode": 0 }))?;
+                self.send_event("terminated", json!({}))
+            }
+            DebugEvent::Stopped(RunResult::Fault(error)) => {
+                self.send_stopped("exception", Some(error.to_string()))
+            }
+            DebugEvent::Stopped(result) => {
+                self.send_stopped("pause", Some(format!("{:?}", result)))
+            }
+        }
+    }
+    /// Sends a `stopped` event
+    fn send_stopped(&mut self, reason: &str, text: Option<String>) -> io::Result<()> {
+        let mut body = json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        });
+        if let Some(text) = text {
+            body["text"] = json!(text);
+        }
+        self.send_event("stopped", body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opcode::Opcode;
+    /// Frames a sequence of requests as a client would send them
+    fn requests(requests: &[Value]) -> Vec<u8> {
+        let mut input = vec![];
+        for (seq, request) in requests.iter().enumerate() {
+            let mut request = request.clone();
+            request["seq"] = json!(seq + 1);
+            request["type"] = json!("request");
+            let content = request.to_string();
+            write!(
+                input,
+                "Content-Length: {}\r\n\r\n{}",
+                content.len(),
+                content
+            )
+            .unwrap();
+        }
+        input
+    }
+    /// Splits the server's output into messages
+    fn messages(output: &[u8]) -> Vec<Value> {
+        let mut input = output;
+        let mut server = DapServer::new(&mut input, vec![]);
+        let mut messages = vec![];
+        while let Some(message) = server.read_message().unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+    /// Runs a scripted session against a program, returning the server's messages
+    fn session(name: &str, program: &[u8], script: &[Value]) -> Vec<Value> {
+        let path =
+            std::env::temp_dir().join(format!("iridium-dap-{}-{}.bin", std::process::id(), name));
+        std::fs::write(&path, program).unwrap();
+        let mut script = script.to_vec();
+        script.insert(0, json!({ "command": "initialize", "arguments": {} }));
+        script.insert(
+            1,
+            json!({
+                "command": "launch",
+                "arguments": { "program": path.to_str().unwrap(), "stopOnEntry": true },
+            }),
+        );
+        let input = requests(&script);
+        let mut output = vec![];
+        DapServer::new(&input[..], &mut output).run().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        messages(&output)
+    }
+    /// Finds the response to the request with the given command
+    fn response<'a>(messages: &'a [Value], command: &str) -> &'a Value {
+        messages
+            .iter()
+            .find(|message| message["type"] == "response" && message["command"] == command)
+            .unwrap()
+    }
+    /// Lists the names of the events that were sent, in order
+    fn events(messages: &[Value]) -> Vec<String> {
+        messages
+            .iter()
+            .filter(|message| message["type"] == "event")
+            .map(|message| match message["body"]["reason"].as_str() {
+                Some(reason) => format!("{}: {}", message["event"].as_str().unwrap(), reason),
+                None => message["event"].as_str().unwrap().to_owned(),
+            })
+            .collect()
+    }
+    #[test]
+    fn test_breakpoint_session() {
+        let program = [
+            Opcode::NOP as u8,
+            Opcode::EXT2 as u8,
+            Opcode::LOADI as u8,
+            2,
+            1,
+            244,
+            Opcode::NOP as u8,
+            Opcode::STOP as u8,
+        ];
+        let messages = session(
+            "breakpoints",
+            &program,
+            &[
+                json!({
+                    "command": "setBreakpoints",
+                    "arguments": { "source": { "path": "main.rb" }, "breakpoints": [{ "line": 3 }] },
+                }),
+                json!({
+                    "command": "setInstructionBreakpoints",
+                    "arguments": { "breakpoints": [{ "instructionReference": "6" }, { "instructionReference": "3" }] },
+                }),
+                json!({ "command": "configurationDone" }),
+                json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+                json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+                json!({ "command": "scopes", "arguments": { "frameId": 0 } }),
+                json!({ "command": "variables", "arguments": { "variablesReference": 1 } }),
+                json!({ "command": "next", "arguments": { "threadId": 1 } }),
+                json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+                json!({ "command": "next", "arguments": { "threadId": 1 } }),
+                json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+                json!({ "command": "disconnect" }),
+            ],
+        );
+        let responses = messages
+            .iter()
+            .filter(|message| message["type"] == "response")
+            .map(|message| {
+                (
+                    message["command"].as_str().unwrap(),
+                    message["success"] == true,
+                )
+            })
+            .collect::<Vec<_>>();
+        // Ensure that execution requests after the program has terminated are rejected
+        assert_eq!(
+            &responses[responses.len() - 4..],
+            &[
+                ("continue", true),
+                ("next", false),
+                ("continue", false),
+                ("disconnect", true)
+            ]
+        );
+        assert!(responses[..responses.len() - 4]
+            .iter()
+            .all(|(_, success)| *success));
+        assert_eq!(
+            response(&messages, "setBreakpoints")["body"]["breakpoints"][0]["verified"],
+            false
+        );
+        assert_eq!(
+            response(&messages, "setInstructionBreakpoints")["body"]["breakpoints"][0]["verified"],
+            true
+        );
+        assert_eq!(
+            response(&messages, "setInstructionBreakpoints")["body"]["breakpoints"][1]["verified"],
+            false
+        );
+        assert_eq!(
+            response(&messages, "stackTrace")["body"]["stackFrames"][0]
+                ["instructionPointerReference"],
+            "6"
+        );
+        assert_eq!(
+            response(&messages, "scopes")["body"]["scopes"][0]["name"],
+            "Registers"
+        );
+        let variables = &response(&messages, "variables")["body"]["variables"];
+        assert_eq!(variables[2]["name"], "r2");
+        assert_eq!(variables[2]["value"], "500");
+        assert_eq!(
+            events(&messages),
+            vec![
+                "initialized",
+                "stopped: entry",
+                "stopped: instruction breakpoint",
+                "stopped: step",
+                "exited",
+                "terminated",
+            ]
+        );
+    }
+    #[test]
+    fn test_fault_session() {
+        let messages = session(
+            "fault",
+            &[Opcode::NOP as u8, Opcode::LOADNIL as u8, 0],
+            &[
+                json!({ "command": "configurationDone" }),
+                json!({ "command": "stepIn", "arguments": { "threadId": 1 } }),
+                json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+                json!({ "command": "evaluate", "arguments": { "expression": "r0" } }),
+            ],
+        );
+        assert_eq!(
+            events(&messages),
+            vec![
+                "initialized",
+                "stopped: entry",
+                "stopped: step",
+                "stopped: exception"
+            ]
+        );
+        let stopped = messages
+            .iter()
+            .rev()
+            .find(|message| message["type"] == "event");
+        assert_eq!(
+            stopped.unwrap()["body"]["text"],
+            "Unrecognized opcode LOADNIL found."
+        );
+        assert_eq!(response(&messages, "evaluate")["success"], false);
+    }
+    #[test]
+    fn test_launch_rejects_invalid_program() {
+        let messages = session(
+            "invalid",
+            &[Opcode::NOP as u8, 200],
+            &[json!({ "command": "configurationDone" })],
+        );
+        let launch = response(&messages, "launch");
+        assert_eq!(launch["success"], false);
+        assert_eq!(
+            launch["message"],
+            "Verification failed at pc 1: 200 is not a valid opcode."
+        );
+        assert_eq!(events(&messages), vec!["initialized"]);
+        assert_eq!(response(&messages, "configurationDone")["success"], false);
+    }
+    #[test]
+    fn test_invalid_header() {
+        for input in [
+            "Content-Type: application/json\r\n\r\n{}",
+            "Content-Length: two\r\n\r\n{}",
+        ] {
+            let mut output = vec![];
+            let error = DapServer::new(input.as_bytes(), &mut output)
+                .run()
+                .unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert!(output.is_empty());
+        }
+    }
+    #[test]
+    fn test_requires_launch() {
+        let input = requests(&[json!({ "command": "threads" })]);
+        let mut output = vec![];
+        DapServer::new(&input[..], &mut output).run().unwrap();
+        let messages = messages(&output);
+        assert_eq!(messages[0]["success"], false);
+        assert_eq!(messages[0]["message"], "No program has been launched");
+    }
+}
@@ -0,0 +1,227 @@
+use crate::vm::{RunResult, VM};
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+
+#[derive(Debug, PartialEq)]
+/// Describes why the debugger paused execution
+pub enum DebugEvent {
+    /// Execution paused before executing the instruction at a breakpoint
+    Breakpoint(usize),
+    /// A single instruction was executed
+    Stepped,
+    /// The Virtual Machine stopped running
+    Stopped(RunResult),
+}
+
+#[derive(Debug)]
+/// Controls the execution of a Virtual Machine one instruction at a time
+///
+/// Breakpoints are set by program counter. Execution can be resumed until the next breakpoint is
+/// reached, or advanced by a single instruction, with the VM's registers available for inspection
+/// whenever it is paused.
+pub struct Debugger {
+    vm: VM,
+    breakpoints: BTreeSet<usize>,
+}
+
+impl Debugger {
+    /// Creates a debugger that controls the given Virtual Machine
+    pub fn new(vm: VM) -> Debugger {
+        Debugger {
+            vm,
+            breakpoints: BTreeSet::new(),
+        }
+    }
+    /// Returns the Virtual Machine being debugged
+    pub fn vm(&self) -> &VM {
+        &self.vm
+    }
+    /// Returns the Virtual Machine being debugged, allowing it to be modified
+    pub fn vm_mut(&mut self) -> &mut VM {
+        &mut self.vm
+    }
+    /// Sets a breakpoint at the given program counter
+    ///
+    /// Returns false if a breakpoint was already set there, or if no instruction starts there
+    /// (in which case the breakpoint could never be reached).
+    pub fn set_breakpoint(&mut self, pc: usize) -> bool {
+        self.vm.is_instruction_start(pc) && self.breakpoints.insert(pc)
+    }
+    /// Clears the breakpoint at the given program counter
+    ///
+    /// Returns false if no breakpoint was set there.
+    pub fn clear_breakpoint(&mut self, pc: usize) -> bool {
+        self.breakpoints.remove(&pc)
+    }
+    /// Returns the program counters of all breakpoints, in ascending order
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+    /// Executes a single instruction
+    pub fn step(&mut self) -> DebugEvent {
+        match self.vm.run_once() {
+            Some(result) => DebugEvent::Stopped(result),
+            None => DebugEvent::Stepped,
+        }
+    }
+    /// Executes instructions until a breakpoint is reached or the Virtual Machine stops
+    ///
+    /// The current instruction is always executed, so resuming from a breakpoint makes progress.
+    pub fn resume(&mut self) -> DebugEvent {
+        loop {
+            if let Some(result) = self.vm.run_once() {
+                return DebugEvent::Stopped(result);
+            }
+            let pc = self.vm.pc();
+            if self.breakpoints.contains(&pc) {
+                return DebugEvent::Breakpoint(pc);
+            }
+        }
+    }
+    /// Runs an interactive debugging session, reading commands from `input` until it is exhausted
+    /// or the `quit` command is given
+    ///
+    /// Supported commands:
+    /// * `break <pc>` / `b <pc>` - sets a breakpoint
+    /// * `delete <pc>` / `d <pc>` - clears a breakpoint
+    /// * `step` / `s` - executes a single instruction
+    /// * `continue` / `c` - executes until a breakpoint is reached or the VM stops
+    /// * `registers` / `r` - prints the contents of the register file
+    /// * `where` / `w` - prints the next instruction to be executed
+    /// * `quit` / `q` - ends the session
+    pub fn repl<R: BufRead, W: Write>(&mut self, input: R, mut output: W) -> io::Result<()> {
+        self.print_location(&mut output)?;
+        for line in input.lines() {
+            let line = line?;
+            let mut words = line.split_whitespace();
+            let command = match words.next() {
+                Some(command) => command,
+                None => continue,
+            };
+            let pc = words.next().map(str::parse::<usize>);
+            match (command, pc) {
+                ("break", Some(Ok(pc))) | ("b", Some(Ok(pc))) => {
+                    if !self.vm.is_instruction_start(pc) {
+                        writeln!(output, "pc {} is not an instruction boundary", pc)?;
+                    } else if self.breakpoints.insert(pc) {
+                        writeln!(output, "Breakpoint set at pc {}", pc)?;
+                    } else {
+                        writeln!(output, "Breakpoint already set at pc {}", pc)?;
+                    }
+                }
+                ("delete", Some(Ok(pc))) | ("d", Some(Ok(pc))) => {
+                    if self.clear_breakpoint(pc) {
+                        writeln!(output, "Breakpoint cleared at pc {}", pc)?;
+                    } else {
+                        writeln!(output, "No breakpoint at pc {}", pc)?;
+                    }
+                }
+                ("step", None) | ("s", None) => {
+                    let event = self.step();
+                    self.print_event(&mut output, event)?;
+                }
+                ("continue", None) | ("c", None) => {
+                    let event = self.resume();
+                    self.print_event(&mut output, event)?;
+                }
+                ("registers", None) | ("r", None) => {
+                    for (index, value) in self.vm.registers().iter().enumerate() {
+                        writeln!(output, "r{} = {}", index, value)?;
+                    }
+                }
+                ("where", None) | ("w", None) => self.print_location(&mut output)?,
+                ("quit", None) | ("q", None) => break,
+                _ => writeln!(output, "Unrecognized command: {}", line.trim())?,
+            }
+        }
+        Ok(())
+    }
+    /// Prints the outcome of a step or resume
+    fn print_event<W: Write>(&mut self, output: &mut W, event: DebugEvent) -> io::Result<()> {
+        match event {
+            DebugEvent::Breakpoint(pc) => writeln!(output, "Breakpoint reached at pc {}", pc)?,
+            DebugEvent::Stepped => {}
+            DebugEvent::Stopped(result) => writeln!(output, "Stopped: {:?}", result)?,
+        }
+        self.print_location(output)
+    }
+    /// Prints the program counter and the next instruction to be executed
+    fn print_location<W: Write>(&mut self, output: &mut W) -> io::Result<()> {
+        let pc = self.vm.pc();
+        match self.vm.peek_instruction() {
+            Ok(Some(instruction)) => writeln!(output, "pc {}: {}", pc, instruction),
+            Ok(None) => writeln!(output, "pc {}: end of program", pc),
+            Err(error) => writeln!(output, "pc {}: {}", pc, error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opcode::Opcode;
+    fn test_debugger() -> Debugger {
+        let mut vm = VM::new();
+        vm.load_program(vec![
+            Opcode::NOP as u8,
+            Opcode::MOVE as u8,
+            1,
+            0,
+            Opcode::NOP as u8,
+            Opcode::STOP as u8,
+        ]);
+        Debugger::new(vm)
+    }
+    #[test]
+    fn test_breakpoints() {
+        let mut debugger = test_debugger();
+        assert!(debugger.set_breakpoint(4));
+        assert!(debugger.set_breakpoint(1));
+        assert!(!debugger.set_breakpoint(4));
+        // Ensure that breakpoints can only be set where instructions start
+        assert!(!debugger.set_breakpoint(2));
+        assert!(!debugger.set_breakpoint(6));
+        assert_eq!(debugger.breakpoints().collect::<Vec<_>>(), vec![1, 4]);
+        assert_eq!(debugger.resume(), DebugEvent::Breakpoint(1));
+        assert_eq!(debugger.resume(), DebugEvent::Breakpoint(4));
+        assert_eq!(debugger.resume(), DebugEvent::Stopped(RunResult::Halted));
+        assert!(debugger.clear_breakpoint(1));
+        assert!(!debugger.clear_breakpoint(1));
+    }
+    #[test]
+    fn test_step() {
+        let mut debugger = test_debugger();
+        assert_eq!(debugger.step(), DebugEvent::Stepped);
+        assert_eq!(debugger.vm().pc(), 1);
+        assert_eq!(debugger.step(), DebugEvent::Stepped);
+        assert_eq!(debugger.vm().pc(), 4);
+        assert_eq!(debugger.step(), DebugEvent::Stepped);
+        assert_eq!(debugger.step(), DebugEvent::Stopped(RunResult::Halted));
+    }
+    #[test]
+    fn test_repl() {
+        let mut debugger = test_debugger();
+        let input = b"b 4\nb 2\nb 4\nc\nr\nfoo\ns\nw\nq\ns\n";
+        let mut output = vec![];
+        debugger.repl(&input[..], &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines = output.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "pc 0: NOP");
+        assert_eq!(lines[1], "Breakpoint set at pc 4");
+        assert_eq!(lines[2], "pc 2 is not an instruction boundary");
+        assert_eq!(lines[3], "Breakpoint already set at pc 4");
+        assert_eq!(lines[4], "Breakpoint reached at pc 4");
+        assert_eq!(lines[5], "pc 4: NOP");
+        // Every register is printed, followed by the remaining commands' output
+        let registers = debugger.vm().registers().len();
+        assert_eq!(lines[6], "r0 = 0");
+        assert!(lines[6..6 + registers]
+            .iter()
+            .all(|line| line.starts_with('r')));
+        assert_eq!(
+            &lines[6 + registers..],
+            &["Unrecognized command: foo", "pc 5: STOP", "pc 5: STOP"]
+        );
+        // Commands after `quit` are ignored, as no more lines follow
+    }
+}
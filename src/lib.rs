@@ -0,0 +1,9 @@
+pub mod config;
+pub mod coverage;
+pub mod dap;
+pub mod debugger;
+pub mod opcode;
+pub mod profile;
+pub mod trace;
+pub mod verifier;
+pub mod vm;
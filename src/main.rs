@@ -1,6 +1,58 @@
-pub mod opcode;
-pub mod vm;
+use iridium::dap::DapServer;
+use iridium::debugger::Debugger;
+use iridium::vm::{RunResult, VM};
+use std::io;
+use std::process;
 
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (debug, path) = match args.as_slice() {
+        [flag] if flag == "--dap" => {
+            let stdin = io::stdin();
+            if let Err(error) = DapServer::new(stdin.lock(), io::stdout()).run() {
+                eprintln!("Debug adapter error: {}", error);
+                process::exit(1);
+            }
+            return;
+        }
+        [path] => (false, path),
+        [flag, path] if flag == "--debug" => (true, path),
+        _ => {
+            eprintln!("Usage: iridium [--debug] <bytecode file>");
+            eprintln!("       iridium --dap");
+            process::exit(2);
+        }
+    };
+    let program = match std::fs::read(path) {
+        Ok(program) => program,
+        Err(error) => {
+            eprintln!("Could not read {}: {}", path, error);
+            process::exit(1);
+        }
+    };
+    let mut vm = VM::new();
+    vm.load_program(program);
+    if let Err(error) = vm.verify() {
+        eprintln!("{}", error);
+        process::exit(1);
+    }
+    if debug {
+        let stdin = io::stdin();
+        if let Err(error) = Debugger::new(vm).repl(stdin.lock(), io::stdout()) {
+            eprintln!("Debugger error: {}", error);
+            process::exit(1);
+        }
+    } else {
+        match vm.run() {
+            RunResult::Halted => {}
+            RunResult::Fault(error) => {
+                eprintln!("{}", error);
+                process::exit(1);
+            }
+            result => {
+                eprintln!("Stopped: {:?}", result);
+                process::exit(1);
+            }
+        }
+    }
 }
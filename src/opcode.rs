@@ -1,7 +1,9 @@
+use std::fmt;
+
 /// Represents a 24-bit value, implemented as a tuple of 3 `u8` values
 pub type U24 = (u8, u8, u8);
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[allow(non_camel_case_types)]
 /// Defines the operations that are allowed within a virtual machine
 ///
@@ -135,6 +137,23 @@ pub enum Opcode {
     INVALID = 255,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// Describes what an opcode's argument refers to
+pub enum OperandKind {
+    /// A register index
+    Register,
+    /// An index into the irep's literal pool
+    Pool,
+    /// An index into the irep's symbol table
+    Symbol,
+    /// An index into the irep's child ireps
+    Irep,
+    /// An absolute program counter to jump to
+    Jump,
+    /// A literal value, count, or bitfield
+    Immediate,
+}
+
 #[derive(Default)]
 /// Represents the arity and nature of the arguments for an opcode
 pub struct OpcodeArity {
@@ -154,7 +173,7 @@ impl Opcode {
     /// * May have 0 - 3 arguments of 8, 16, or 24-bit lengths
     /// * May have signed or unsigned arguments
     /// * May have 8-bit arguments resized to 16-bit arguments when preceded by
-    /// [`EXT1`](#variant.EXT1), [`EXT2`](#variant.EXT2), or [`EXT3`](#variant.EXT3)
+    ///   [`EXT1`](#variant.EXT1), [`EXT2`](#variant.EXT2), or [`EXT3`](#variant.EXT3)
     pub fn arity(&self) -> OpcodeArity {
         match self {
             // No args
@@ -299,9 +318,139 @@ impl Opcode {
             }
         }
     }
+    /// Returns what each of an opcode's arguments refers to, in order
+    ///
+    /// The number of operand kinds returned always matches the opcode's
+    /// [arity](#method.arity).
+    pub fn operand_kinds(&self) -> &'static [OperandKind] {
+        use OperandKind::*;
+        match self {
+            Self::NOP
+            | Self::EXT1
+            | Self::EXT2
+            | Self::EXT3
+            | Self::STOP
+            | Self::MAX
+            | Self::INVALID => &[],
+            Self::LOADI_0
+            | Self::LOADI_1
+            | Self::LOADI_2
+            | Self::LOADI_3
+            | Self::LOADNIL
+            | Self::LOADSELF
+            | Self::LOADT
+            | Self::LOADF
+            | Self::EXCEPT
+            | Self::RAISE
+            | Self::CALL
+            | Self::RETURN
+            | Self::RETURN_BLK
+            | Self::BREAK
+            | Self::ARYCAT
+            | Self::ARYPUSH
+            | Self::STRCAT
+            | Self::RANGE_INC
+            | Self::RANGE_EXC
+            | Self::OCLASS
+            | Self::SCLASS
+            | Self::TCLASS => &[Register],
+            Self::POPERR | Self::EPOP => &[Immediate],
+            Self::EPUSH => &[Irep],
+            Self::ALIAS => &[Symbol],
+            Self::ERR => &[Pool],
+            Self::JMP | Self::ONERR => &[Jump],
+            Self::ENTER => &[Immediate],
+            Self::MOVE | Self::RESCUE | Self::ARRAY2 | Self::AREF | Self::ASET => {
+                &[Register, Register]
+            }
+            Self::LOADL | Self::STRING => &[Register, Pool],
+            Self::LOADSYM
+            | Self::GETGV
+            | Self::SETGV
+            | Self::GETSV
+            | Self::SETSV
+            | Self::GETIV
+            | Self::SETIV
+            | Self::GETCV
+            | Self::SETCV
+            | Self::GETCONST
+            | Self::SETCONST
+            | Self::GETMCNST
+            | Self::SETMCNST
+            | Self::SENDV
+            | Self::SENDVB
+            | Self::KARG
+            | Self::KARG2
+            | Self::CLASS
+            | Self::MODULE
+            | Self::DEF => &[Register, Symbol],
+            Self::LAMBDA | Self::BLOCK | Self::METHOD | Self::EXEC => &[Register, Irep],
+            Self::LOADI
+            | Self::SUPER
+            | Self::ARGARY
+            | Self::BLKPUSH
+            | Self::ADD
+            | Self::SUB
+            | Self::SUBI
+            | Self::MUL
+            | Self::DIV
+            | Self::EQ
+            | Self::LT
+            | Self::LE
+            | Self::GT
+            | Self::GE
+            | Self::ARRAY
+            | Self::APOST
+            | Self::HASH
+            | Self::HASHADD => &[Register, Immediate],
+            Self::UNDEF => &[Symbol, Immediate],
+            Self::JMPIF | Self::JMPNOT => &[Register, Jump],
+            Self::SEND | Self::SENDB => &[Register, Symbol, Immediate],
+            Self::GETUPVAR | Self::SETUPVAR | Self::ADDI => &[Register, Immediate, Immediate],
+        }
+    }
+    /// Returns the amount of fuel consumed when executing an opcode
+    ///
+    /// Most opcodes cost a single unit. Opcodes that dispatch methods or allocate objects are
+    /// more expensive, so that an instruction budget more closely tracks the work being done.
+    /// The [`EXT1`](#variant.EXT1), [`EXT2`](#variant.EXT2), and [`EXT3`](#variant.EXT3)
+    /// modifiers are free, as they are decoded along with the opcode they modify.
+    pub fn cost(&self) -> u32 {
+        match self {
+            // Argument modifiers
+            Self::EXT1 | Self::EXT2 | Self::EXT3 => 0,
+            // Method dispatch
+            Self::SENDV
+            | Self::SENDVB
+            | Self::SEND
+            | Self::SENDB
+            | Self::CALL
+            | Self::SUPER
+            | Self::EXEC => 10,
+            // Allocation
+            Self::ARRAY
+            | Self::ARRAY2
+            | Self::ARYCAT
+            | Self::ARYPUSH
+            | Self::STRING
+            | Self::STRCAT
+            | Self::HASH
+            | Self::HASHADD
+            | Self::LAMBDA
+            | Self::BLOCK
+            | Self::METHOD
+            | Self::RANGE_INC
+            | Self::RANGE_EXC
+            | Self::CLASS
+            | Self::MODULE
+            | Self::SCLASS
+            | Self::DEF => 5,
+            _ => 1,
+        }
+    }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 /// Stores the values of an opcode's arguments for an instruction
 pub enum OpcodeArgs {
     // Zero arguments
@@ -321,10 +470,31 @@ pub enum OpcodeArgs {
     U8U8U8(u8, u8, u8),
 }
 
+impl fmt::Display for OpcodeArgs {
+    /// Formats the arguments as a comma-separated list, with 24-bit arguments shown as a single value
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OpcodeArgs::None => Ok(()),
+            OpcodeArgs::U8(a) => write!(f, "{}", a),
+            OpcodeArgs::U16(a) => write!(f, "{}", a),
+            OpcodeArgs::I16(a) => write!(f, "{}", a),
+            OpcodeArgs::U24((a, b, c)) => {
+                write!(f, "{}", (a as u32) << 16 | (b as u32) << 8 | c as u32)
+            }
+            OpcodeArgs::U8U8(a, b) => write!(f, "{}, {}", a, b),
+            OpcodeArgs::U8I8(a, b) => write!(f, "{}, {}", a, b),
+            OpcodeArgs::U8U16(a, b) => write!(f, "{}, {}", a, b),
+            OpcodeArgs::U8I16(a, b) => write!(f, "{}, {}", a, b),
+            OpcodeArgs::U16U16(a, b) => write!(f, "{}, {}", a, b),
+            OpcodeArgs::U8U8U8(a, b, c) => write!(f, "{}, {}, {}", a, b, c),
+        }
+    }
+}
+
 impl From<u8> for Opcode {
     fn from(v: u8) -> Self {
         if v < Opcode::MAX as u8 {
-            unsafe { std::mem::transmute(v) }
+            unsafe { std::mem::transmute::<u8, Opcode>(v) }
         } else {
             Opcode::INVALID
         }
@@ -415,4 +585,39 @@ mod tests {
             }
         }
     }
+    #[test]
+    fn test_operand_kinds() {
+        assert_eq!(
+            &[OperandKind::Register, OperandKind::Register],
+            Opcode::MOVE.operand_kinds()
+        );
+        assert_eq!(
+            &[OperandKind::Register, OperandKind::Jump],
+            Opcode::JMPIF.operand_kinds()
+        );
+        // Ensures there is an operand kind for every argument
+        for v in 0..Opcode::MAX as u8 {
+            let opcode = Opcode::from(v);
+            assert_eq!(
+                opcode.arity().argc as usize,
+                opcode.operand_kinds().len(),
+                "Opcode {:?} - operand kinds do not match arity",
+                opcode
+            );
+        }
+    }
+    #[test]
+    fn test_args_display() {
+        assert_eq!("", OpcodeArgs::None.to_string());
+        assert_eq!("1, -2", OpcodeArgs::U8I16(1, -2).to_string());
+        assert_eq!("1, 2, 3", OpcodeArgs::U8U8U8(1, 2, 3).to_string());
+        assert_eq!("65793", OpcodeArgs::U24((1, 1, 1)).to_string());
+    }
+    #[test]
+    fn test_cost() {
+        assert_eq!(0, Opcode::EXT1.cost());
+        assert_eq!(1, Opcode::MOVE.cost());
+        assert!(Opcode::SEND.cost() > Opcode::MOVE.cost());
+        assert!(Opcode::ARRAY.cost() > Opcode::MOVE.cost());
+    }
 }
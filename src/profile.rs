@@ -0,0 +1,103 @@
+use crate::opcode::Opcode;
+use crate::trace::Hook;
+use crate::vm::Instruction;
+use std::io::{self, Write};
+
+/// A hook that counts how many times each opcode is executed
+pub struct OpcodeProfiler {
+    counts: Vec<u64>,
+}
+
+impl Default for OpcodeProfiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OpcodeProfiler {
+    /// Creates a profiler with all counts set to zero
+    pub fn new() -> OpcodeProfiler {
+        OpcodeProfiler {
+            counts: vec![0; u8::MAX as usize + 1],
+        }
+    }
+    /// Returns the number of times an opcode was executed
+    pub fn count(&self, opcode: Opcode) -> u64 {
+        self.counts[opcode as usize]
+    }
+    /// Returns the total number of instructions executed
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+    /// Returns the opcodes that were executed along with their counts, most frequent first
+    pub fn counts(&self) -> Vec<(Opcode, u64)> {
+        let mut counts = self
+            .counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(opcode, count)| (Opcode::from(opcode as u8), *count))
+            .collect::<Vec<_>>();
+        counts.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+        counts
+    }
+    /// Writes a table of opcode counts and their share of all instructions executed
+    pub fn write_report<W: Write>(&self, mut output: W) -> io::Result<()> {
+        let total = self.total();
+        writeln!(output, "{:<12} {:>12} {:>8}", "opcode", "count", "percent")?;
+        for (opcode, count) in self.counts() {
+            let percent = count as f64 * 100.0 / total as f64;
+            writeln!(
+                output,
+                "{:<12} {:>12} {:>7.2}%",
+                format!("{:?}", opcode),
+                count,
+                percent
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Hook for OpcodeProfiler {
+    fn before_instruction(&mut self, _pc: usize, instruction: &Instruction, _registers: &[i32]) {
+        self.counts[instruction.opcode() as usize] += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{RunResult, VM};
+    use std::sync::{Arc, Mutex};
+    #[test]
+    fn test_opcode_profiler() {
+        let profiler = Arc::new(Mutex::new(OpcodeProfiler::new()));
+        let mut vm = VM::new();
+        vm.add_hook(Box::new(profiler.clone()));
+        vm.load_program(vec![
+            Opcode::NOP as u8,
+            Opcode::MOVE as u8,
+            1,
+            0,
+            Opcode::NOP as u8,
+            Opcode::NOP as u8,
+            Opcode::STOP as u8,
+        ]);
+        assert_eq!(vm.run(), RunResult::Halted);
+        let profiler = profiler.lock().unwrap();
+        assert_eq!(profiler.count(Opcode::NOP), 3);
+        assert_eq!(profiler.count(Opcode::JMP), 0);
+        assert_eq!(profiler.total(), 5);
+        assert_eq!(
+            profiler.counts(),
+            vec![(Opcode::NOP, 3), (Opcode::MOVE, 1), (Opcode::STOP, 1)]
+        );
+        let mut report = vec![];
+        profiler.write_report(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        let lines = report.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[1], "NOP                     3   60.00%");
+    }
+}
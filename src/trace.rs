@@ -0,0 +1,143 @@
+use crate::vm::{Instruction, RunResult};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Observes the execution of a Virtual Machine
+///
+/// Hooks are added to a VM with [`VM::add_hook`](../vm/struct.VM.html#method.add_hook). To
+/// inspect a hook after the VM has run, add it as an `Arc<Mutex<_>>` and keep a clone.
+///
+/// Hooks must be `Send` so that a VM with hooks can still be moved to another thread.
+pub trait Hook: Send {
+    /// Called before each instruction is executed, with the program counter the instruction was
+    /// decoded from and the contents of the register file
+    fn before_instruction(&mut self, pc: usize, instruction: &Instruction, registers: &[i32]);
+    /// Called each time the VM stops running, with the program counter it stopped at and the
+    /// reason it stopped
+    fn after_run(&mut self, _pc: usize, _result: &RunResult) {}
+}
+
+impl<H: Hook + ?Sized> Hook for Arc<Mutex<H>> {
+    fn before_instruction(&mut self, pc: usize, instruction: &Instruction, registers: &[i32]) {
+        self.lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .before_instruction(pc, instruction, registers);
+    }
+    fn after_run(&mut self, pc: usize, result: &RunResult) {
+        self.lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .after_run(pc, result);
+    }
+}
+
+/// A hook that writes a line to its output for each instruction executed
+///
+/// Each line contains the program counter, the instruction, and any non-zero registers:
+///
+/// ```text
+/// 000005  MOVE 2, 1  ; r1=7
+/// ```
+///
+/// Writing stops at the first I/O error, which is returned by [`finish`](#method.finish).
+pub struct Tracer<W: Write> {
+    output: W,
+    error: Option<io::Error>,
+}
+
+impl Tracer<BufWriter<File>> {
+    /// Creates a tracer that writes to a newly-created file
+    pub fn to_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Tracer::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<W: Write> Tracer<W> {
+    /// Creates a tracer that writes to the given output
+    pub fn new(output: W) -> Tracer<W> {
+        Tracer {
+            output,
+            error: None,
+        }
+    }
+    /// Flushes the trace, returning its output or the first error encountered while writing
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        self.output.flush()?;
+        Ok(self.output)
+    }
+    /// Writes a single line of the trace
+    fn write_line(
+        &mut self,
+        pc: usize,
+        instruction: &Instruction,
+        registers: &[i32],
+    ) -> io::Result<()> {
+        write!(self.output, "{:06}  {}", pc, instruction)?;
+        let mut separator = "  ;";
+        for (index, value) in registers.iter().enumerate() {
+            if *value != 0 {
+                write!(self.output, "{} r{}={}", separator, index, value)?;
+                separator = "";
+            }
+        }
+        writeln!(self.output)
+    }
+}
+
+impl<W: Write + Send> Hook for Tracer<W> {
+    fn before_instruction(&mut self, pc: usize, instruction: &Instruction, registers: &[i32]) {
+        if self.error.is_none() {
+            if let Err(error) = self.write_line(pc, instruction, registers) {
+                self.error = Some(error);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opcode::Opcode;
+    use crate::vm::{RunResult, VM};
+    #[test]
+    fn test_tracer() {
+        let tracer = Arc::new(Mutex::new(Tracer::new(vec![])));
+        let mut vm = VM::new();
+        vm.add_hook(Box::new(tracer.clone()));
+        vm.load_program(vec![
+            Opcode::EXT2 as u8,
+            Opcode::LOADI as u8,
+            1,
+            0,
+            7,
+            Opcode::MOVE as u8,
+            2,
+            1,
+            Opcode::NOP as u8,
+        ]);
+        assert_eq!(vm.run(), RunResult::Halted);
+        drop(vm);
+        let tracer = Arc::try_unwrap(tracer).ok().unwrap().into_inner().unwrap();
+        let output = String::from_utf8(tracer.finish().unwrap()).unwrap();
+        assert_eq!(
+            output,
+            "000000  LOADI 1, 7\n000005  MOVE 2, 1  ; r1=7\n000008  NOP  ; r1=7 r2=7\n"
+        );
+    }
+    #[test]
+    fn test_tracer_to_file() {
+        let path = std::env::temp_dir().join(format!("iridium-trace-{}.log", std::process::id()));
+        let mut tracer = Tracer::to_file(&path).unwrap();
+        let mut vm = VM::new();
+        vm.load_program(vec![Opcode::STOP as u8]);
+        let instruction = vm.peek_instruction().unwrap().unwrap();
+        tracer.before_instruction(0, &instruction, vm.registers());
+        tracer.finish().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "000000  STOP\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,328 @@
+use crate::opcode::{Opcode, OpcodeArgs, OperandKind};
+use crate::vm::{Decoder, VmError};
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::fmt;
+
+#[derive(Debug, PartialEq)]
+/// Describes why a program failed verification
+pub enum VerifyErrorKind {
+    /// An instruction could not be fully decoded
+    Decode(VmError),
+    /// A byte does not correspond to a valid opcode
+    InvalidOpcode(u8),
+    /// An argument extension was applied to an opcode without the 8-bit argument it extends
+    MisplacedExtension { extension: Opcode, opcode: Opcode },
+    /// A register argument is not below the number of registers available
+    RegisterOutOfBounds {
+        register: usize,
+        register_count: usize,
+    },
+    /// A jump target does not land on the start of an instruction or the end of the program
+    InvalidJumpTarget(i64),
+}
+
+#[derive(Debug, PartialEq)]
+/// Represents a problem found while verifying a program
+pub struct VerifyError {
+    /// The program counter of the instruction that failed verification
+    pub pc: usize,
+    /// The check that the instruction failed
+    pub kind: VerifyErrorKind,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Verification failed at pc {}: ", self.pc)?;
+        match &self.kind {
+            VerifyErrorKind::Decode(error) => write!(f, "{}", error),
+            VerifyErrorKind::InvalidOpcode(byte) => write!(f, "{} is not a valid opcode.", byte),
+            VerifyErrorKind::MisplacedExtension { extension, opcode } => write!(
+                f,
+                "{:?} cannot extend the arguments of opcode {:?}.",
+                extension, opcode
+            ),
+            VerifyErrorKind::RegisterOutOfBounds {
+                register,
+                register_count,
+            } => write!(
+                f,
+                "Register {} is out of bounds ({} registers available).",
+                register, register_count
+            ),
+            VerifyErrorKind::InvalidJumpTarget(target) => write!(
+                f,
+                "Jump target {} is not the start of an instruction or the end of the program.",
+                target
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Returns the values of an instruction's arguments, in order
+fn operands(args: OpcodeArgs) -> Vec<i64> {
+    match args {
+        OpcodeArgs::None => vec![],
+        OpcodeArgs::U8(a) => vec![a.into()],
+        OpcodeArgs::U16(a) => vec![a.into()],
+        OpcodeArgs::I16(a) => vec![a.into()],
+        OpcodeArgs::U24((a, b, c)) => {
+            vec![i64::from(a) << 16 | i64::from(b) << 8 | i64::from(c)]
+        }
+        OpcodeArgs::U8U8(a, b) => vec![a.into(), b.into()],
+        OpcodeArgs::U8I8(a, b) => vec![a.into(), b.into()],
+        OpcodeArgs::U8U16(a, b) => vec![a.into(), b.into()],
+        OpcodeArgs::U8I16(a, b) => vec![a.into(), b.into()],
+        OpcodeArgs::U16U16(a, b) => vec![a.into(), b.into()],
+        OpcodeArgs::U8U8U8(a, b, c) => vec![a.into(), b.into(), c.into()],
+    }
+}
+
+/// Checks that a program is well-formed before it is executed
+///
+/// Each instruction is walked using its opcode's [arity](../opcode/enum.Opcode.html#method.arity),
+/// verifying that:
+/// * every byte in the opcode position is a valid opcode, and every instruction decodes fully
+/// * `EXT1`, `EXT2`, and `EXT3` only prefix opcodes whose extended arguments are 8-bit
+/// * register arguments are below `register_count`
+/// * jump targets land on the start of an instruction, or on the end of the program (which halts
+///   the VM)
+///
+/// Pool, symbol, and child irep indexes are not checked, as programs do not yet carry those tables.
+pub fn verify(program: &[u8], register_count: usize) -> Result<(), VerifyError> {
+    let mut starts = BTreeSet::new();
+    let mut jumps = vec![];
+    let mut decoder = Decoder::new(program, 0);
+    loop {
+        let pc = decoder.pc();
+        if pc >= program.len() {
+            break;
+        }
+        let error = |kind| Err(VerifyError { pc, kind });
+        // Check that any argument extension applies to the opcode that follows it
+        let extension = Opcode::from(program[pc]);
+        if let Opcode::EXT1 | Opcode::EXT2 | Opcode::EXT3 = extension {
+            // A trailing extension has no opcode to extend, so the program was truncated
+            let byte = match program.get(pc + 1) {
+                Some(&byte) => byte,
+                None => {
+                    return error(VerifyErrorKind::Decode(VmError::UnexpectedEof {
+                        opcode: extension,
+                        argc: 1,
+                    }))
+                }
+            };
+            let opcode = Opcode::from(byte);
+            let arity = opcode.arity();
+            let extends = match extension {
+                Opcode::EXT1 => arity.arg1_size == 8,
+                Opcode::EXT2 => arity.arg2_size == 8,
+                _ => arity.arg1_size == 8 && arity.arg2_size == 8,
+            };
+            if !extends {
+                return error(VerifyErrorKind::MisplacedExtension { extension, opcode });
+            }
+        }
+        let instruction = match decoder.decode_instruction() {
+            Ok(Some(instruction)) => instruction,
+            Ok(None) => break,
+            Err(decode_error) => return error(VerifyErrorKind::Decode(decode_error)),
+        };
+        if instruction.opcode() == Opcode::INVALID {
+            return error(VerifyErrorKind::InvalidOpcode(program[decoder.pc() - 1]));
+        }
+        let kinds = instruction.opcode().operand_kinds();
+        for (kind, value) in kinds.iter().zip(operands(instruction.args())) {
+            match kind {
+                OperandKind::Register if value as usize >= register_count => {
+                    return error(VerifyErrorKind::RegisterOutOfBounds {
+                        register: value as usize,
+                        register_count,
+                    });
+                }
+                OperandKind::Jump => jumps.push((pc, value)),
+                _ => {}
+            }
+        }
+        starts.insert(pc);
+    }
+    // Jump targets can only be checked once every instruction has been found
+    for (pc, target) in jumps {
+        // Signed targets (such as those of ONERR) can be negative
+        let valid = usize::try_from(target)
+            .map(|target| target == program.len() || starts.contains(&target))
+            .unwrap_or(false);
+        if !valid {
+            return Err(VerifyError {
+                pc,
+                kind: VerifyErrorKind::InvalidJumpTarget(target),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_valid_program() {
+        let program = [
+            Opcode::EXT2 as u8,
+            Opcode::LOADI as u8,
+            3,
+            1,
+            244,
+            Opcode::MOVE as u8,
+            1,
+            3,
+            Opcode::JMP as u8,
+            0,
+            0,
+            Opcode::STOP as u8,
+        ];
+        assert_eq!(verify(&program, 4), Ok(()));
+        assert_eq!(verify(&[], 0), Ok(()));
+    }
+    #[test]
+    fn test_truncated_instruction() {
+        let program = [Opcode::NOP as u8, Opcode::MOVE as u8, 1];
+        assert_eq!(
+            verify(&program, 32),
+            Err(VerifyError {
+                pc: 1,
+                kind: VerifyErrorKind::Decode(VmError::UnexpectedEof {
+                    opcode: Opcode::MOVE,
+                    argc: 2
+                })
+            })
+        );
+    }
+    #[test]
+    fn test_truncated_extension() {
+        for extension in [Opcode::EXT1, Opcode::EXT2, Opcode::EXT3] {
+            assert_eq!(
+                verify(&[Opcode::NOP as u8, extension as u8], 32),
+                Err(VerifyError {
+                    pc: 1,
+                    kind: VerifyErrorKind::Decode(VmError::UnexpectedEof {
+                        opcode: extension,
+                        argc: 1
+                    })
+                })
+            );
+        }
+    }
+    #[test]
+    fn test_onerr_target() {
+        let program = [Opcode::ONERR as u8, 0, 3, Opcode::STOP as u8];
+        assert_eq!(verify(&program, 32), Ok(()));
+        assert_eq!(
+            verify(&[Opcode::ONERR as u8, 0, 2, Opcode::STOP as u8], 32),
+            Err(VerifyError {
+                pc: 0,
+                kind: VerifyErrorKind::InvalidJumpTarget(2)
+            })
+        );
+        // Ensure that negative targets are reported as they were encoded
+        let program = [Opcode::ONERR as u8, 0xff, 0xfe, Opcode::STOP as u8];
+        let error = verify(&program, 32).unwrap_err();
+        assert_eq!(error.kind, VerifyErrorKind::InvalidJumpTarget(-2));
+        assert_eq!(
+            error.to_string(),
+            "Verification failed at pc 0: Jump target -2 is not the start of an instruction or the end of the program."
+        );
+    }
+    #[test]
+    fn test_jump_to_end() {
+        // The VM halts when it jumps to the end of the program
+        let program = [Opcode::JMP as u8, 0, 3];
+        assert_eq!(verify(&program, 32), Ok(()));
+        let mut vm = crate::vm::VM::new();
+        vm.load_program(program.to_vec());
+        assert_eq!(vm.run(), crate::vm::RunResult::Halted);
+        assert_eq!(
+            verify(&[Opcode::JMP as u8, 0, 4], 32).unwrap_err().kind,
+            VerifyErrorKind::InvalidJumpTarget(4)
+        );
+    }
+    #[test]
+    fn test_invalid_opcode() {
+        let error = verify(&[Opcode::NOP as u8, 200], 32).unwrap_err();
+        assert_eq!(error.pc, 1);
+        assert_eq!(error.kind, VerifyErrorKind::InvalidOpcode(200));
+        assert_eq!(
+            error.to_string(),
+            "Verification failed at pc 1: 200 is not a valid opcode."
+        );
+    }
+    #[test]
+    fn test_misplaced_extension() {
+        for (extension, opcode) in [
+            (Opcode::EXT1, Opcode::JMP),
+            (Opcode::EXT2, Opcode::JMPIF),
+            (Opcode::EXT3, Opcode::LOADNIL),
+            (Opcode::EXT1, Opcode::EXT2),
+        ] {
+            assert_eq!(
+                verify(&[extension as u8, opcode as u8, 0, 0, 0], 32),
+                Err(VerifyError {
+                    pc: 0,
+                    kind: VerifyErrorKind::MisplacedExtension { extension, opcode }
+                })
+            );
+        }
+    }
+    #[test]
+    fn test_register_out_of_bounds() {
+        let program = [Opcode::MOVE as u8, 1, 4];
+        assert_eq!(verify(&program, 5), Ok(()));
+        assert_eq!(
+            verify(&program, 4),
+            Err(VerifyError {
+                pc: 0,
+                kind: VerifyErrorKind::RegisterOutOfBounds {
+                    register: 4,
+                    register_count: 4
+                }
+            })
+        );
+        // Ensure that extended register arguments are checked
+        let program = [Opcode::EXT1 as u8, Opcode::LOADNIL as u8, 1, 0];
+        assert_eq!(
+            verify(&program, 32).unwrap_err().kind,
+            VerifyErrorKind::RegisterOutOfBounds {
+                register: 256,
+                register_count: 32
+            }
+        );
+    }
+    #[test]
+    fn test_invalid_jump_target() {
+        // Jumps into the middle of an instruction, including past an argument extension
+        for target in [1, 4, 6, 9] {
+            let program = [
+                Opcode::MOVE as u8,
+                1,
+                2,
+                Opcode::EXT2 as u8,
+                Opcode::LOADI as u8,
+                0,
+                0,
+                0,
+                Opcode::JMP as u8,
+                0,
+                target,
+            ];
+            assert_eq!(
+                verify(&program, 32),
+                Err(VerifyError {
+                    pc: 8,
+                    kind: VerifyErrorKind::InvalidJumpTarget(target.into())
+                })
+            );
+        }
+    }
+}
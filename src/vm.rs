@@ -1,6 +1,12 @@
+use crate::config::VmConfig;
 use crate::opcode::{Opcode, OpcodeArgs, OpcodeArity, U24};
+use crate::trace::Hook;
+use crate::verifier::{self, VerifyError};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 /// Respresents a single instruction to be executed within a Virtual Machine
 ///
 /// Instructions consist of an opcode and up to 3 arguments.
@@ -9,27 +15,147 @@ pub struct Instruction {
     args: OpcodeArgs,
 }
 
+impl Instruction {
+    /// Creates an instruction from an opcode and its arguments
+    pub fn new(opcode: Opcode, args: OpcodeArgs) -> Instruction {
+        Instruction { opcode, args }
+    }
+    /// Returns the instruction's opcode
+    pub fn opcode(&self) -> Opcode {
+        self.opcode
+    }
+    /// Returns the instruction's arguments
+    pub fn args(&self) -> OpcodeArgs {
+        self.args
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.args {
+            OpcodeArgs::None => write!(f, "{:?}", self.opcode),
+            args => write!(f, "{:?} {}", self.opcode, args),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
-/// Tracks the state of a Virtual Machine
-pub struct VM {
-    registers: [i32; 32],
-    pc: usize,
-    program: Vec<u8>,
-    halted: bool,
-    last_error: Option<String>,
+/// Describes why a Virtual Machine stopped executing instructions
+pub enum RunResult {
+    /// The program executed a `STOP` instruction or ran out of instructions to execute
+    Halted,
+    /// The program ran out of fuel before the next instruction could be executed
+    ///
+    /// The program counter is left at the unexecuted instruction, so the program can be resumed
+    /// by calling [`add_fuel`](struct.VM.html#method.add_fuel) and running the VM again.
+    FuelExhausted,
+    /// The program was stopped by an [`InterruptHandle`](struct.InterruptHandle.html)
+    ///
+    /// Interrupts are checked after backward jumps, so the program can be resumed by running the
+    /// VM again.
+    Interrupted,
+    /// The program could not continue because an instruction failed to decode or execute
+    ///
+    /// The program counter is left at the faulting instruction, and running the VM again reports
+    /// the same fault until another program is loaded.
+    Fault(VmError),
 }
 
-impl VM {
-    /// Creates a new Virtual Machine instance
-    pub fn new() -> VM {
-        VM {
-            registers: [0; 32],
-            pc: 0,
-            program: vec![],
-            halted: false,
-            last_error: None,
+#[derive(Clone, Debug, PartialEq)]
+/// Represents an error encountered while decoding or executing an instruction
+pub enum VmError {
+    /// The bytecode reached EOF before all of an opcode's arguments could be read
+    UnexpectedEof { opcode: Opcode, argc: u8 },
+    /// The arity of an opcode's arguments could not be determined
+    UnknownArity(Opcode),
+    /// An opcode was decoded with arguments it does not accept
+    UnrecognizedArguments { opcode: Opcode, args: OpcodeArgs },
+    /// An opcode is not supported by the Virtual Machine
+    UnrecognizedOpcode(Opcode),
+    /// An instruction referenced a register beyond the end of the register file
+    RegisterOutOfBounds(usize),
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::UnexpectedEof { opcode, argc } => write!(
+                f,
+                "Could not decode arguments for opcode {:?} - {} argument(s) needed, but bytecode reached EOF.",
+                opcode, argc
+            ),
+            VmError::UnknownArity(opcode) => write!(
+                f,
+                "Could not decode arguments for opcode {:?} - arity could not be determined.",
+                opcode
+            ),
+            VmError::UnrecognizedArguments { opcode, args } => write!(
+                f,
+                "Unrecognized arguments {:?} for opcode {:?} found.",
+                args, opcode
+            ),
+            VmError::UnrecognizedOpcode(opcode) => {
+                write!(f, "Unrecognized opcode {:?} found.", opcode)
+            }
+            VmError::RegisterOutOfBounds(index) => {
+                write!(f, "Register {} is out of bounds.", index)
+            }
         }
     }
+}
+
+impl std::error::Error for VmError {}
+
+#[derive(Clone, Debug, Default)]
+/// A thread-safe handle used to stop a running Virtual Machine
+///
+/// Handles are obtained by calling [`VM::interrupt_handle`](struct.VM.html#method.interrupt_handle)
+/// and can be cloned and sent to other threads (such as a watchdog). Calling
+/// [`interrupt`](#method.interrupt) requests that the VM stop, which it will do the next time it
+/// checks for interrupts, returning [`RunResult::Interrupted`](enum.RunResult.html#variant.Interrupted).
+pub struct InterruptHandle {
+    requested: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+    /// Requests that the VM stop running
+    ///
+    /// The request is kept until the VM handles it or a new program is loaded, even if the VM
+    /// stops for another reason first.
+    pub fn interrupt(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+    /// Checks whether an interrupt has been requested, clearing the request
+    fn take(&self) -> bool {
+        self.requested.swap(false, Ordering::SeqCst)
+    }
+}
+
+#[derive(Default)]
+/// Stores the hooks that observe a Virtual Machine's execution
+struct Hooks(Vec<Box<dyn Hook>>);
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Hooks({})", self.0.len())
+    }
+}
+
+/// Decodes instructions from a program's bytecode
+pub struct Decoder<'a> {
+    program: &'a [u8],
+    pc: usize,
+}
+
+impl<'a> Decoder<'a> {
+    /// Creates a decoder that reads the program starting from the given program counter
+    pub fn new(program: &'a [u8], pc: usize) -> Decoder<'a> {
+        Decoder { program, pc }
+    }
+    /// Returns the offset of the next byte to be read
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
     /// Checks whether the progam counter has reached the end of the program (there are no more bytes to read)
     fn eof(&self) -> bool {
         self.eof_with_offset(0)
@@ -44,12 +170,17 @@ impl VM {
             self.pc + (offset as usize) >= self.program.len()
         }
     }
-    /// Generates the error message to be used when decoding an instruction fails due to EOF
-    fn decode_error(&self, opcode: Opcode, arity: OpcodeArity) -> Result<Instruction, String> {
-        Err(format!("Could to decode arguments for opcode {:?} - {} argument(s) needed, but bytecode reached EOF.", opcode, arity.argc))
+    /// Generates the error to be used when decoding an instruction fails due to EOF
+    fn decode_error<T>(&self, opcode: Opcode, arity: OpcodeArity) -> Result<T, VmError> {
+        Err(VmError::UnexpectedEof {
+            opcode,
+            argc: arity.argc,
+        })
     }
     /// Decodes an instruction and advances the program counter accordingly
-    fn decode_instruction(&mut self) -> Result<Instruction, String> {
+    ///
+    /// Returns `Ok(None)` when the end of the program has been reached.
+    pub fn decode_instruction(&mut self) -> Result<Option<Instruction>, VmError> {
         let mut op_ext: Option<Opcode> = None;
 
         // First, check for an opcode that extends arguments
@@ -58,7 +189,7 @@ impl VM {
                 op_ext = op;
                 self.decode_opcode()
             }
-            op @ _ => op,
+            op => op,
         };
 
         // Next, attempt to build an instruction
@@ -67,15 +198,11 @@ impl VM {
             let mut arity = opcode.arity();
             // Adjust for any extended arguments
             match op_ext {
-                Some(Opcode::EXT1) => {
-                    if arity.arg1_size == 8 {
-                        arity.arg1_size = 16;
-                    }
+                Some(Opcode::EXT1) if arity.arg1_size == 8 => {
+                    arity.arg1_size = 16;
                 }
-                Some(Opcode::EXT2) => {
-                    if arity.arg2_size == 8 {
-                        arity.arg2_size = 16;
-                    }
+                Some(Opcode::EXT2) if arity.arg2_size == 8 => {
+                    arity.arg2_size = 16;
                 }
                 Some(Opcode::EXT3) => {
                     if arity.arg1_size == 8 {
@@ -230,31 +357,16 @@ impl VM {
                     }
                 }
                 // Invalid args
-                _ => {
-                    return Err(format!(
-                    "Could not decode arguments for opcode {:?} - arity could not be determined.",
-                    opcode
-                ))
-                }
+                _ => return Err(VmError::UnknownArity(opcode)),
             };
-            Ok(Instruction {
-                opcode: opcode,
-                args: args,
-            })
+            Ok(Some(Instruction { opcode, args }))
         } else {
-            Err(
-                "Could not decode the next instruction. End of program has been reached."
-                    .to_owned(),
-            )
+            Ok(None)
         }
     }
     /// Converts the next 8 bits into an opcode and advances the program counter
     fn decode_opcode(&mut self) -> Option<Opcode> {
-        if let Some(opcode) = self.next_8_bits() {
-            Some(Opcode::from(opcode))
-        } else {
-            None
-        }
+        self.next_8_bits().map(Opcode::from)
     }
     /// Reads the next 8 bits of the program and advances the program counter
     fn next_8_bits(&mut self) -> Option<u8> {
@@ -280,68 +392,421 @@ impl VM {
             return None;
         }
         let result = (
-            self.program[self.pc] as u8,
-            self.program[self.pc + 1] as u8,
-            self.program[self.pc + 2] as u8,
+            self.program[self.pc],
+            self.program[self.pc + 1],
+            self.program[self.pc + 2],
         );
         self.pc += 3;
         Some(result)
     }
+}
+
+/// Marks an instruction index that does not refer to a predecoded instruction
+const NO_INDEX: u32 = u32::MAX;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// An instruction that was decoded ahead of time
+struct PredecodedInstruction {
+    instruction: Instruction,
+    /// The program counter the instruction was decoded from
+    pc: usize,
+    /// The program counter of the instruction that follows it
+    next_pc: usize,
+    /// The index of the instruction that a `JMP` jumps to, or `NO_INDEX`
+    target: u32,
+}
+
+#[derive(Debug, Default, PartialEq)]
+/// Stores a program's instructions after decoding them ahead of time
+///
+/// Instructions are decoded in order from the start of the program until the end is reached or an
+/// instruction fails to decode, so the instruction that follows the one at index `i` is at index
+/// `i + 1`. Jump targets are resolved to instruction indexes as well, so that the VM can dispatch
+/// by index without any further parsing of the bytecode or lookups by program counter.
+struct Predecoded {
+    /// Maps each program counter to the index of the instruction starting there, or `NO_INDEX`
+    offsets: Vec<u32>,
+    instructions: Vec<PredecodedInstruction>,
+}
+
+impl Predecoded {
+    /// Decodes as much of a program as possible
+    fn new(program: &[u8]) -> Predecoded {
+        let mut offsets = vec![NO_INDEX; program.len()];
+        let mut instructions = vec![];
+        let mut decoder = Decoder::new(program, 0);
+        loop {
+            let pc = decoder.pc();
+            match decoder.decode_instruction() {
+                Ok(Some(instruction)) => {
+                    offsets[pc] = instructions.len() as u32;
+                    instructions.push(PredecodedInstruction {
+                        instruction,
+                        pc,
+                        next_pc: decoder.pc(),
+                        target: NO_INDEX,
+                    });
+                }
+                _ => break,
+            }
+        }
+        // Targets can only be resolved once every instruction has been found
+        for predecoded in instructions.iter_mut() {
+            if let (Opcode::JMP, OpcodeArgs::U16(target)) =
+                (predecoded.instruction.opcode, predecoded.instruction.args)
+            {
+                predecoded.target = offsets.get(target as usize).copied().unwrap_or(NO_INDEX);
+            }
+        }
+        Predecoded {
+            offsets,
+            instructions,
+        }
+    }
+    /// Returns the index of the instruction starting at a program counter, or `NO_INDEX`
+    fn index(&self, pc: usize) -> u32 {
+        self.offsets.get(pc).copied().unwrap_or(NO_INDEX)
+    }
+    /// Returns the instruction at an index, if it starts at the given program counter
+    fn get(&self, index: u32, pc: usize) -> Option<&PredecodedInstruction> {
+        self.instructions
+            .get(index as usize)
+            .filter(|predecoded| predecoded.pc == pc)
+    }
+}
+
+#[derive(Debug)]
+/// Tracks the state of a Virtual Machine
+pub struct VM {
+    registers: Vec<i32>,
+    pc: usize,
+    program: Vec<u8>,
+    predecode: bool,
+    predecoded: Predecoded,
+    /// The index of the predecoded instruction expected at the program counter
+    index: u32,
+    halted: bool,
+    /// The error that stopped the VM, which is reported again by every later run
+    fault: Option<VmError>,
+    fuel: Option<u64>,
+    interrupt: InterruptHandle,
+    hooks: Hooks,
+}
+
+impl PartialEq for VM {
+    /// Compares the execution state of two Virtual Machines, ignoring their interrupt handles,
+    /// hooks, and position in the predecoded instructions
+    fn eq(&self, other: &Self) -> bool {
+        self.registers == other.registers
+            && self.pc == other.pc
+            && self.program == other.program
+            && self.predecode == other.predecode
+            && self.predecoded == other.predecoded
+            && self.halted == other.halted
+            && self.fault == other.fault
+            && self.fuel == other.fuel
+    }
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VM {
+    /// Creates a new Virtual Machine instance with the default configuration
+    pub fn new() -> VM {
+        VM::with_config(VmConfig::default())
+    }
+    /// Creates a new Virtual Machine instance with the given configuration
+    pub fn with_config(config: VmConfig) -> VM {
+        VM {
+            registers: vec![0; config.register_count],
+            pc: 0,
+            program: vec![],
+            predecode: config.predecode,
+            predecoded: Predecoded::default(),
+            index: 0,
+            halted: false,
+            fault: None,
+            fuel: config.fuel,
+            interrupt: InterruptHandle::default(),
+            hooks: Hooks::default(),
+        }
+    }
+    /// Loads a program, resetting the program counter so that it executes from the beginning
+    ///
+    /// Unless disabled by the VM's configuration, the program is decoded ahead of time.
+    pub fn load_program(&mut self, program: Vec<u8>) {
+        self.predecoded = if self.predecode {
+            Predecoded::new(&program)
+        } else {
+            Predecoded::default()
+        };
+        self.program = program;
+        self.pc = 0;
+        self.index = 0;
+        self.halted = false;
+        self.fault = None;
+        // Discard any interrupt requested for a previous program
+        self.interrupt.take();
+    }
+    /// Checks whether an instruction starts at the given program counter
+    ///
+    /// Instructions are found by decoding the program in order from its start, so a program
+    /// counter that falls within an instruction's arguments (or after an instruction that fails
+    /// to decode) is not an instruction start. The instructions decoded when the program was
+    /// loaded are used when available.
+    pub fn is_instruction_start(&self, pc: usize) -> bool {
+        if self.predecoded.offsets.len() == self.program.len() {
+            // Predecoding stops at an instruction that fails to decode, which still has a start
+            let end = self
+                .predecoded
+                .instructions
+                .last()
+                .map_or(0, |predecoded| predecoded.next_pc);
+            return self.predecoded.index(pc) != NO_INDEX || (pc == end && pc < self.program.len());
+        }
+        let mut decoder = Decoder::new(&self.program, 0);
+        while decoder.pc() < pc {
+            match decoder.decode_instruction() {
+                Ok(Some(_)) => {}
+                _ => return false,
+            }
+        }
+        decoder.pc() == pc && pc < self.program.len()
+    }
+    /// Verifies that the loaded program is well-formed and only uses the VM's registers
+    ///
+    /// See [`verifier::verify`](../verifier/fn.verify.html) for the checks performed.
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        verifier::verify(&self.program, self.registers.len())
+    }
+    /// Returns the current value of the program counter
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+    /// Returns the contents of the register file
+    pub fn registers(&self) -> &[i32] {
+        &self.registers
+    }
+    /// Decodes the next instruction to be executed without advancing the program counter
+    ///
+    /// Returns `Ok(None)` when the end of the program has been reached.
+    pub fn peek_instruction(&mut self) -> Result<Option<Instruction>, VmError> {
+        let pc = self.pc;
+        let instruction = self.decode_instruction();
+        self.pc = pc;
+        instruction
+    }
+    /// Adds a hook that is called before each instruction is executed and whenever the VM stops
+    ///
+    /// Hooks are called in the order they were added.
+    pub fn add_hook(&mut self, hook: Box<dyn Hook>) {
+        self.hooks.0.push(hook);
+    }
+    /// Removes and returns all of the VM's hooks
+    pub fn take_hooks(&mut self) -> Vec<Box<dyn Hook>> {
+        std::mem::take(&mut self.hooks.0)
+    }
+    /// Returns a handle that can be used to interrupt the VM from another thread
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+    /// Returns the amount of fuel remaining, or `None` if the VM's fuel is unlimited
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+    /// Sets the amount of fuel available for executing instructions
+    ///
+    /// Each instruction consumes fuel according to its opcode's [cost](../opcode/enum.Opcode.html#method.cost).
+    /// Passing `None` allows the VM to execute an unlimited number of instructions.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+    /// Adds fuel to a VM with a limited amount of fuel
+    ///
+    /// Has no effect if the VM's fuel is unlimited.
+    pub fn add_fuel(&mut self, amount: u64) {
+        if let Some(fuel) = self.fuel {
+            self.fuel = Some(fuel.saturating_add(amount));
+        }
+    }
+    /// Reads the value of a register
+    fn register(&self, index: usize) -> Result<i32, VmError> {
+        self.registers
+            .get(index)
+            .copied()
+            .ok_or(VmError::RegisterOutOfBounds(index))
+    }
+    /// Writes a value to a register
+    fn set_register(&mut self, index: usize, value: i32) -> Result<(), VmError> {
+        let register = self
+            .registers
+            .get_mut(index)
+            .ok_or(VmError::RegisterOutOfBounds(index))?;
+        *register = value;
+        Ok(())
+    }
+    /// Checks whether the progam counter has reached the end of the program (there are no more bytes to read)
+    fn eof(&self) -> bool {
+        self.eof_with_offset(0)
+    }
+    /// Checks whether the progam counter would reach the end of the program given the requested offset
+    /// # Panics
+    /// Panics if the offset causes the program counter to underflow
+    fn eof_with_offset(&self, offset: isize) -> bool {
+        Decoder::new(&self.program, self.pc).eof_with_offset(offset)
+    }
+    /// Decodes an instruction and advances the program counter accordingly
+    ///
+    /// Returns `Ok(None)` when the end of the program has been reached.
+    ///
+    /// Instructions that were decoded ahead of time are used when available. Otherwise (such as
+    /// after jumping into the middle of an instruction), the bytecode is decoded as it is read.
+    fn decode_instruction(&mut self) -> Result<Option<Instruction>, VmError> {
+        self.fetch()
+            .map(|fetched| fetched.map(|(instruction, _)| instruction))
+    }
+    /// Decodes an instruction like [`decode_instruction`](#method.decode_instruction), along with
+    /// the index of its jump target if it was decoded ahead of time
+    fn fetch(&mut self) -> Result<Option<(Instruction, u32)>, VmError> {
+        // The index follows the program counter while executing predecoded instructions, and only
+        // needs to be looked up after the program counter has moved some other way
+        let mut predecoded = self.predecoded.get(self.index, self.pc);
+        if predecoded.is_none() {
+            self.index = self.predecoded.index(self.pc);
+            predecoded = self.predecoded.get(self.index, self.pc);
+        }
+        if let Some(predecoded) = predecoded {
+            let fetched = (predecoded.instruction, predecoded.target);
+            self.pc = predecoded.next_pc;
+            self.index += 1;
+            return Ok(Some(fetched));
+        }
+        let mut decoder = Decoder::new(&self.program, self.pc);
+        let instruction = decoder.decode_instruction();
+        self.pc = decoder.pc();
+        instruction.map(|instruction| instruction.map(|instruction| (instruction, NO_INDEX)))
+    }
     /// Executes a single instruction and advances the program counter
-    /// Returns true if successful
-    pub fn run_once(&mut self) -> bool {
-        self.execute_instruction()
+    ///
+    /// Returns `None` if the Virtual Machine can continue executing, or the reason it stopped
+    pub fn run_once(&mut self) -> Option<RunResult> {
+        let result = self.step();
+        if let Some(result) = &result {
+            for hook in self.hooks.0.iter_mut() {
+                hook.after_run(self.pc, result);
+            }
+        }
+        result
+    }
+    /// Executes a single instruction, returning the reason the VM stopped (if it did)
+    fn step(&mut self) -> Option<RunResult> {
+        if let Some(error) = &self.fault {
+            return Some(RunResult::Fault(error.clone()));
+        }
+        if self.halted || self.eof() {
+            return Some(RunResult::Halted);
+        }
+        let start = self.pc;
+        let (instruction, target) = match self.fetch() {
+            Ok(Some(fetched)) => fetched,
+            Ok(None) => return Some(RunResult::Halted),
+            Err(error) => return self.fault(start, error),
+        };
+        // Leave the instruction unexecuted if there is not enough fuel to pay for it
+        if let Some(fuel) = self.fuel {
+            let cost = u64::from(instruction.opcode.cost());
+            if cost > fuel {
+                self.pc = start;
+                return Some(RunResult::FuelExhausted);
+            }
+            self.fuel = Some(fuel - cost);
+        }
+        match self.execute(start, instruction, target) {
+            Ok(()) if self.halted => Some(RunResult::Halted),
+            // Loops are formed by backward jumps, so check for interrupts after each one
+            Ok(()) if self.pc <= start && self.interrupt.take() => Some(RunResult::Interrupted),
+            Ok(()) => None,
+            Err(error) => self.fault(start, error),
+        }
+    }
+    /// Stops the VM at the instruction that caused an error, so that it cannot be run past it
+    fn fault(&mut self, pc: usize, error: VmError) -> Option<RunResult> {
+        self.pc = pc;
+        self.fault = Some(error.clone());
+        Some(RunResult::Fault(error))
     }
     /// Executes instructions until the program is halted or an error is encountered
-    pub fn run(&mut self) {
-        while self.last_error == None && !self.halted && !self.eof() && self.execute_instruction() {
+    pub fn run(&mut self) -> RunResult {
+        loop {
+            if let Some(result) = self.run_once() {
+                return result;
+            }
         }
     }
     /// Executes a single instruction
-    pub fn execute_instruction(&mut self) -> bool {
-        let instruction = self.decode_instruction();
-        if let Err(reason) = instruction {
-            self.last_error = Some(reason);
-            return false;
+    ///
+    /// Unlike [`run_once`](#method.run_once), no fuel is consumed.
+    pub fn execute_instruction(&mut self) -> Result<(), VmError> {
+        let start = self.pc;
+        match self.fetch()? {
+            Some((instruction, target)) => self.execute(start, instruction, target),
+            None => Ok(()),
+        }
+    }
+    /// Executes an instruction that has already been decoded from the given program counter
+    ///
+    /// `target` is the index of the predecoded instruction a jump lands on, or `NO_INDEX` if it is
+    /// not known.
+    fn execute(&mut self, pc: usize, instruction: Instruction, target: u32) -> Result<(), VmError> {
+        for hook in self.hooks.0.iter_mut() {
+            hook.before_instruction(pc, &instruction, &self.registers);
         }
-        let instruction = instruction.unwrap();
-        match &instruction.opcode {
+        match instruction.opcode {
             Opcode::NOP => {}
             Opcode::MOVE => match instruction.args {
                 OpcodeArgs::U8U8(a, b) => {
-                    self.registers[a as usize] = self.registers[b as usize];
+                    self.set_register(a as usize, self.register(b as usize)?)?;
                 }
-                _ => {
-                    panic!(
-                        "Unrecognized arguments {:?} for opcode {:?} found. Terminating.",
-                        instruction.args, instruction.opcode
-                    );
+                args => {
+                    return Err(VmError::UnrecognizedArguments {
+                        opcode: instruction.opcode,
+                        args,
+                    })
+                }
+            },
+            Opcode::JMP => match instruction.args {
+                OpcodeArgs::U16(a) => {
+                    self.pc = a as usize;
+                    self.index = target;
+                }
+                args => {
+                    return Err(VmError::UnrecognizedArguments {
+                        opcode: instruction.opcode,
+                        args,
+                    })
                 }
             },
             Opcode::STOP => {
                 self.halted = true;
-                return false;
             }
             Opcode::LOADI => match instruction.args {
                 OpcodeArgs::U8I16(a, b) => {
-                    println!("{}, {}", a, b);
-                    self.registers[a as usize] = b as i32;
+                    self.set_register(a as usize, b as i32)?;
                 }
-                _ => {
-                    panic!(
-                        "Unrecognized arguments {:?} for opcode {:?} found. Terminating.",
-                        instruction.args, instruction.opcode
-                    );
+                args => {
+                    return Err(VmError::UnrecognizedArguments {
+                        opcode: instruction.opcode,
+                        args,
+                    })
                 }
             },
-            _ => {
-                panic!(
-                    "Unrecognized opcode {:?} found. Terminating.",
-                    instruction.opcode
-                );
-            }
+            opcode => return Err(VmError::UnrecognizedOpcode(opcode)),
         }
-        true
+        Ok(())
     }
 }
 
@@ -357,17 +822,16 @@ mod tests {
     fn test_empty_program() {
         let mut test_vm = VM::new();
         test_vm.program = vec![];
-        test_vm.run();
-        assert_eq!(test_vm.last_error, None);
+        assert_eq!(test_vm.run(), RunResult::Halted);
     }
     #[test]
     fn test_opcode_stop() {
         let mut test_vm = VM::new();
         test_vm.program = vec![Opcode::STOP as u8, 0, 0, 0];
-        test_vm.run();
+        assert_eq!(test_vm.run(), RunResult::Halted);
         assert_eq!(test_vm.pc, 1);
-        assert_eq!(test_vm.halted, true);
-        test_vm.run();
+        assert!(test_vm.halted);
+        assert_eq!(test_vm.run(), RunResult::Halted);
         // Ensure that program can not advance further if halted
         assert_eq!(test_vm.pc, 1);
     }
@@ -375,25 +839,373 @@ mod tests {
     fn test_opcode_load() {
         let mut test_vm = VM::new();
         test_vm.program = vec![Opcode::EXT2 as u8, Opcode::LOADI as u8, 0, 1, 244];
-        test_vm.run();
+        assert_eq!(test_vm.run(), RunResult::Halted);
         assert_eq!(test_vm.registers[0], 500);
-        assert_eq!(test_vm.eof(), true);
-        assert_eq!(test_vm.last_error, None);
+        assert!(test_vm.eof());
     }
     #[test]
-    #[should_panic(expected = "Unrecognized opcode INVALID found. Terminating.")]
     fn test_opcode_invalid() {
         let mut test_vm = VM::new();
         test_vm.program = vec![200, 0, 0, 0];
-        test_vm.run();
+        assert_eq!(
+            test_vm.run(),
+            RunResult::Fault(VmError::UnrecognizedOpcode(Opcode::INVALID))
+        );
+        assert_eq!(
+            VmError::UnrecognizedOpcode(Opcode::INVALID).to_string(),
+            "Unrecognized opcode INVALID found."
+        );
+    }
+    #[test]
+    fn test_fault_stops_vm() {
+        let mut test_vm = VM::new();
+        test_vm.load_program(vec![
+            Opcode::LOADNIL as u8,
+            0,
+            Opcode::MOVE as u8,
+            1,
+            0,
+            Opcode::STOP as u8,
+        ]);
+        let fault = RunResult::Fault(VmError::UnrecognizedOpcode(Opcode::LOADNIL));
+        assert_eq!(test_vm.run(), fault);
+        assert_eq!(test_vm.pc, 0);
+        // Ensure that running again does not skip past the faulting instruction
+        assert_eq!(test_vm.run(), fault);
+        assert_eq!(test_vm.run_once(), Some(fault));
+        assert_eq!(test_vm.pc, 0);
+        // Loading a program clears the fault
+        test_vm.load_program(vec![Opcode::STOP as u8]);
+        assert_eq!(test_vm.run(), RunResult::Halted);
+    }
+    #[test]
+    fn test_truncated_instruction() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![Opcode::MOVE as u8, 1];
+        assert_eq!(
+            test_vm.run(),
+            RunResult::Fault(VmError::UnexpectedEof {
+                opcode: Opcode::MOVE,
+                argc: 2
+            })
+        );
+    }
+    #[test]
+    fn test_run_once() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![Opcode::NOP as u8, Opcode::STOP as u8, Opcode::NOP as u8];
+        assert_eq!(test_vm.run_once(), None);
+        assert_eq!(test_vm.run_once(), Some(RunResult::Halted));
+        assert_eq!(test_vm.run_once(), Some(RunResult::Halted));
+        assert_eq!(test_vm.pc, 2);
+    }
+    #[test]
+    fn test_fuel() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![
+            Opcode::NOP as u8,
+            Opcode::MOVE as u8,
+            1,
+            0,
+            Opcode::NOP as u8,
+        ];
+        test_vm.set_fuel(Some(2));
+        assert_eq!(test_vm.run(), RunResult::FuelExhausted);
+        assert_eq!(test_vm.pc, 4);
+        assert_eq!(test_vm.fuel(), Some(0));
+        // Ensure that the program does not advance until more fuel is provided
+        assert_eq!(test_vm.run(), RunResult::FuelExhausted);
+        assert_eq!(test_vm.pc, 4);
+        test_vm.add_fuel(1);
+        assert_eq!(test_vm.run(), RunResult::Halted);
+        assert_eq!(test_vm.pc, 5);
+        assert_eq!(test_vm.fuel(), Some(0));
+    }
+    #[test]
+    fn test_fuel_resumes_extended_instruction() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![Opcode::EXT2 as u8, Opcode::LOADI as u8, 0, 1, 244];
+        test_vm.set_fuel(Some(0));
+        assert_eq!(test_vm.run(), RunResult::FuelExhausted);
+        assert_eq!(test_vm.pc, 0);
+        test_vm.set_fuel(None);
+        assert_eq!(test_vm.run(), RunResult::Halted);
+        assert_eq!(test_vm.registers[0], 500);
+        test_vm.add_fuel(10);
+        assert_eq!(test_vm.fuel(), None);
+    }
+    #[test]
+    fn test_opcode_jmp() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![
+            Opcode::JMP as u8,
+            0,
+            4,
+            Opcode::STOP as u8,
+            Opcode::NOP as u8,
+        ];
+        assert_eq!(test_vm.run(), RunResult::Halted);
+        assert_eq!(test_vm.pc, 5);
+        assert!(!test_vm.halted);
+    }
+    #[test]
+    fn test_interrupt() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![Opcode::NOP as u8, Opcode::JMP as u8, 0, 0];
+        let handle = test_vm.interrupt_handle();
+        handle.interrupt();
+        assert_eq!(test_vm.run(), RunResult::Interrupted);
+        assert_eq!(test_vm.pc, 0);
+        // Ensure that the interrupt request is cleared so the program can be resumed
+        test_vm.set_fuel(Some(4));
+        assert_eq!(test_vm.run(), RunResult::FuelExhausted);
+    }
+    #[test]
+    fn test_late_interrupt() {
+        let mut test_vm = VM::new();
+        test_vm.load_program(vec![Opcode::STOP as u8]);
+        assert_eq!(test_vm.run(), RunResult::Halted);
+        // An interrupt that arrives after the run has ended must not stop the next program
+        test_vm.interrupt_handle().interrupt();
+        test_vm.load_program(vec![Opcode::NOP as u8, Opcode::JMP as u8, 0, 0]);
+        test_vm.set_fuel(Some(10));
+        assert_eq!(test_vm.run(), RunResult::FuelExhausted);
+        // An interrupt that is requested before the VM stops for another reason is kept until
+        // the program is resumed
+        test_vm.load_program(vec![Opcode::NOP as u8, Opcode::JMP as u8, 0, 0]);
+        test_vm.set_fuel(Some(1));
+        test_vm.interrupt_handle().interrupt();
+        assert_eq!(test_vm.run(), RunResult::FuelExhausted);
+        assert_eq!(test_vm.pc, 1);
+        test_vm.add_fuel(1000);
+        assert_eq!(test_vm.run(), RunResult::Interrupted);
+        assert_eq!(test_vm.pc, 0);
+    }
+    #[test]
+    fn test_interrupt_from_thread() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![Opcode::JMP as u8, 0, 0];
+        let handle = test_vm.interrupt_handle();
+        let watchdog = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            handle.interrupt();
+        });
+        assert_eq!(test_vm.run(), RunResult::Interrupted);
+        watchdog.join().unwrap();
+    }
+    #[test]
+    fn test_with_config() {
+        let mut test_vm = VM::with_config(VmConfig::new().registers(4).fuel(1));
+        assert_eq!(test_vm.registers.len(), 4);
+        assert_eq!(test_vm.fuel(), Some(1));
+        test_vm.program = vec![Opcode::MOVE as u8, 3, 0, Opcode::MOVE as u8, 4, 0];
+        assert_eq!(test_vm.run(), RunResult::FuelExhausted);
+        test_vm.add_fuel(1);
+        assert_eq!(
+            test_vm.run(),
+            RunResult::Fault(VmError::RegisterOutOfBounds(4))
+        );
+    }
+    #[test]
+    fn test_load_program() {
+        let mut test_vm = VM::new();
+        test_vm.load_program(vec![Opcode::MOVE as u8, 1, 0, Opcode::STOP as u8]);
+        assert_eq!(
+            test_vm.peek_instruction(),
+            Ok(Some(Instruction {
+                opcode: Opcode::MOVE,
+                args: OpcodeArgs::U8U8(1, 0)
+            }))
+        );
+        assert_eq!(test_vm.pc(), 0);
+        assert_eq!(test_vm.run(), RunResult::Halted);
+        assert_eq!(test_vm.pc(), 4);
+        // Ensure that loading a program clears the halted state
+        test_vm.load_program(vec![Opcode::NOP as u8]);
+        assert_eq!(test_vm.run_once(), None);
+        assert_eq!(test_vm.pc(), 1);
+    }
+    #[test]
+    fn test_instruction_display() {
+        let instruction = Instruction {
+            opcode: Opcode::LOADI,
+            args: OpcodeArgs::U8I16(0, 500),
+        };
+        assert_eq!(instruction.to_string(), "LOADI 0, 500");
+        let instruction = Instruction {
+            opcode: Opcode::STOP,
+            args: OpcodeArgs::None,
+        };
+        assert_eq!(instruction.to_string(), "STOP");
+    }
+    #[test]
+    fn test_hooks() {
+        use std::sync::{Arc, Mutex};
+        struct Recorder(Vec<(usize, Opcode, i32)>, Vec<usize>);
+        impl Hook for Recorder {
+            fn before_instruction(
+                &mut self,
+                pc: usize,
+                instruction: &Instruction,
+                registers: &[i32],
+            ) {
+                self.0.push((pc, instruction.opcode(), registers[1]));
+            }
+            fn after_run(&mut self, pc: usize, result: &RunResult) {
+                assert_eq!(result, &RunResult::Halted);
+                self.1.push(pc);
+            }
+        }
+        let recorder = Arc::new(Mutex::new(Recorder(vec![], vec![])));
+        let mut test_vm = VM::new();
+        test_vm.add_hook(Box::new(recorder.clone()));
+        test_vm.load_program(vec![
+            Opcode::EXT2 as u8,
+            Opcode::LOADI as u8,
+            1,
+            0,
+            7,
+            Opcode::MOVE as u8,
+            2,
+            1,
+            Opcode::STOP as u8,
+        ]);
+        assert_eq!(test_vm.run(), RunResult::Halted);
+        assert_eq!(
+            recorder.lock().unwrap().0,
+            vec![
+                (0, Opcode::LOADI, 0),
+                (5, Opcode::MOVE, 7),
+                (8, Opcode::STOP, 7)
+            ]
+        );
+        assert_eq!(recorder.lock().unwrap().1, vec![9]);
+        assert_eq!(test_vm.take_hooks().len(), 1);
+        assert_eq!(test_vm.take_hooks().len(), 0);
+    }
+    #[test]
+    fn test_predecode() {
+        let program = vec![
+            Opcode::EXT2 as u8,
+            Opcode::LOADI as u8,
+            1,
+            0,
+            7,
+            Opcode::MOVE as u8,
+            2,
+            1,
+            Opcode::JMP as u8,
+            0,
+            12,
+            Opcode::STOP as u8,
+            Opcode::MOVE as u8,
+            3,
+        ];
+        let predecoded = Predecoded::new(&program);
+        assert_eq!(predecoded.instructions.len(), 4);
+        assert_eq!(
+            predecoded.get(0, 0),
+            Some(&PredecodedInstruction {
+                instruction: Instruction::new(Opcode::LOADI, OpcodeArgs::U8I16(1, 7)),
+                pc: 0,
+                next_pc: 5,
+                target: NO_INDEX,
+            })
+        );
+        assert_eq!(predecoded.index(1), NO_INDEX);
+        // Ensure that an index is only used at the program counter of its instruction
+        assert_eq!(predecoded.get(0, 5), None);
+        // The jump target was not decoded, so it is left unresolved
+        assert_eq!(predecoded.index(8), 2);
+        assert_eq!(predecoded.instructions[2].target, NO_INDEX);
+        assert_eq!(predecoded.index(11), 3);
+        assert_eq!(predecoded.instructions[3].next_pc, 12);
+        // Ensure that decoding stops at an instruction that can not be decoded
+        assert_eq!(predecoded.index(12), NO_INDEX);
+        // Ensure that jump targets are resolved to instruction indexes
+        let looped = Predecoded::new(&[
+            Opcode::NOP as u8,
+            Opcode::NOP as u8,
+            Opcode::JMP as u8,
+            0,
+            1,
+        ]);
+        assert_eq!(looped.instructions[2].target, 1);
+        let mut test_vm = VM::new();
+        test_vm.load_program(program.clone());
+        assert_eq!(
+            test_vm.run(),
+            RunResult::Fault(VmError::UnexpectedEof {
+                opcode: Opcode::MOVE,
+                argc: 2
+            })
+        );
+        assert_eq!(&test_vm.registers[0..3], &[0, 7, 7]);
+        // Ensure that the same program behaves identically without predecoding
+        let mut comp_vm = VM::with_config(VmConfig::new().predecode(false));
+        comp_vm.load_program(program);
+        assert_eq!(comp_vm.predecoded, Predecoded::default());
+        assert_eq!(
+            comp_vm.run(),
+            RunResult::Fault(VmError::UnexpectedEof {
+                opcode: Opcode::MOVE,
+                argc: 2
+            })
+        );
+        assert_eq!(test_vm.registers, comp_vm.registers);
+        assert_eq!(test_vm.pc, comp_vm.pc);
+    }
+    #[test]
+    fn test_predecode_jump_into_instruction() {
+        let mut test_vm = VM::new();
+        // Jumps into the arguments of the MOVE, which decode as a STOP instruction
+        test_vm.load_program(vec![
+            Opcode::JMP as u8,
+            0,
+            4,
+            Opcode::MOVE as u8,
+            Opcode::STOP as u8,
+            0,
+        ]);
+        assert_eq!(test_vm.run(), RunResult::Halted);
+        assert!(test_vm.halted);
+        assert_eq!(test_vm.pc, 5);
+    }
+    #[test]
+    fn test_is_instruction_start() {
+        // Ensure that the predecoded instructions agree with decoding from the start
+        for predecode in [true, false] {
+            let mut test_vm = VM::with_config(VmConfig::new().predecode(predecode));
+            test_vm.load_program(vec![
+                Opcode::NOP as u8,
+                Opcode::EXT2 as u8,
+                Opcode::LOADI as u8,
+                0,
+                0,
+                1,
+                Opcode::STOP as u8,
+                Opcode::MOVE as u8,
+                0,
+            ]);
+            let starts = (0..10)
+                .filter(|pc| test_vm.is_instruction_start(*pc))
+                .collect::<Vec<_>>();
+            assert_eq!(starts, vec![0, 1, 6, 7]);
+        }
+    }
+    #[test]
+    fn test_vm_is_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<VM>();
+        assert_send::<InterruptHandle>();
     }
     #[test]
     fn test_eof() {
         let mut test_vm = VM::new();
         test_vm.program = vec![];
-        assert_eq!(test_vm.eof(), true);
+        assert!(test_vm.eof());
         test_vm.program = vec![0];
-        assert_eq!(test_vm.eof(), false);
+        assert!(!test_vm.eof());
         test_vm.program = vec![0, 0, 0, 0, 0, 0];
         for v in 0..10 {
             test_vm.pc = v;
@@ -405,14 +1217,14 @@ mod tests {
         let mut test_vm = VM::new();
         test_vm.program = vec![0, 0, 0, 0, 0, 0];
         test_vm.pc = 2;
-        assert_eq!(test_vm.eof_with_offset(0), false);
-        assert_eq!(test_vm.eof_with_offset(3), false);
-        assert_eq!(test_vm.eof_with_offset(4), true);
-        assert_eq!(test_vm.eof_with_offset(8), true);
+        assert!(!test_vm.eof_with_offset(0));
+        assert!(!test_vm.eof_with_offset(3));
+        assert!(test_vm.eof_with_offset(4));
+        assert!(test_vm.eof_with_offset(8));
         test_vm.pc = 10;
-        assert_eq!(test_vm.eof_with_offset(0), true);
-        assert_eq!(test_vm.eof_with_offset(-5), false);
-        assert_eq!(test_vm.eof_with_offset(-10), false);
+        assert!(test_vm.eof_with_offset(0));
+        assert!(!test_vm.eof_with_offset(-5));
+        assert!(!test_vm.eof_with_offset(-10));
     }
     #[test]
     fn test_op_nop() {